@@ -0,0 +1,260 @@
+// Command-line client for a running cluster, e.g.
+//
+//     queuectl --rank 1 enqueue 5
+//     queuectl --addr 10.0.0.7:8002 dequeue
+//     queuectl watch --interval-ms 250
+//     queuectl complexity --ranks 4
+//
+// Exit codes: 0 success, 1 error reported by the node, 2 usage error, 3 node unreachable,
+// 4 timeout, 5 Dequeue found the queue empty.
+use async_queue_client::{Client, ClientConfig, Error, DEFAULT_BASE_PORT, DEFAULT_TIMEOUT};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::env;
+use std::net::SocketAddr;
+use std::process::exit;
+use std::thread;
+use std::time::Duration;
+
+const USAGE: &str = "usage: queuectl [--rank N | --addr HOST:PORT] [--host HOST] [--base-port PORT]
+                [--timeout-ms MS] <command>
+
+commands:
+  enqueue <value>             enqueue an integer
+  dequeue                     dequeue and print the value
+  status                      print operation and message counters
+  dump                        print the node's full state
+  watch [--interval-ms MS]    print the clock, queue and pending Dequeues as they change
+  complexity [--ranks N]      print the messages each operation cost, summed over ranks
+                              0..N-1 (default: only the selected node)";
+
+enum Command {
+    Enqueue(i32),
+    Dequeue,
+    Status,
+    Dump,
+    Watch(Duration),
+    Complexity(Option<u16>),
+}
+
+struct Options {
+    rank: u16,
+    addr: Option<SocketAddr>,
+    host: String,
+    base_port: u16,
+    timeout: Duration,
+    command: Command,
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    exit(2)
+}
+
+fn parse_value<T: std::str::FromStr>(arg: &str, value: Option<String>) -> T {
+    let value = value.unwrap_or_else(|| usage_error(&format!("Missing value for {}", arg)));
+    value
+        .parse()
+        .unwrap_or_else(|_| usage_error(&format!("Invalid value for {}: {}", arg, value)))
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Options {
+    let mut rank = 0;
+    let mut addr = None;
+    let mut host = "127.0.0.1".to_string();
+    let mut base_port = DEFAULT_BASE_PORT;
+    let mut timeout = DEFAULT_TIMEOUT;
+
+    let command = loop {
+        let arg = args
+            .next()
+            .unwrap_or_else(|| usage_error("Missing command"));
+        match arg.as_str() {
+            "--rank" => rank = parse_value(&arg, args.next()),
+            "--addr" => addr = Some(parse_value(&arg, args.next())),
+            "--host" => host = parse_value(&arg, args.next()),
+            "--base-port" => base_port = parse_value(&arg, args.next()),
+            "--timeout-ms" => timeout = Duration::from_millis(parse_value(&arg, args.next())),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0)
+            }
+            "enqueue" => break Command::Enqueue(parse_value("enqueue", args.next())),
+            "dequeue" => break Command::Dequeue,
+            "status" => break Command::Status,
+            "dump" => break Command::Dump,
+            "watch" => {
+                let mut interval = Duration::from_millis(500);
+                while let Some(arg) = args.next() {
+                    match arg.as_str() {
+                        "--interval-ms" => {
+                            interval = Duration::from_millis(parse_value(&arg, args.next()))
+                        }
+                        _ => usage_error(&format!("Unknown argument {}", arg)),
+                    }
+                }
+                break Command::Watch(interval);
+            }
+            "complexity" => {
+                let mut ranks = None;
+                while let Some(arg) = args.next() {
+                    match arg.as_str() {
+                        "--ranks" => ranks = Some(parse_value(&arg, args.next())),
+                        _ => usage_error(&format!("Unknown argument {}", arg)),
+                    }
+                }
+                break Command::Complexity(ranks);
+            }
+            _ => usage_error(&format!("Unknown argument {}", arg)),
+        }
+    };
+    if let Some(arg) = args.next() {
+        usage_error(&format!("Unexpected argument {}", arg));
+    }
+    Options {
+        rank,
+        addr,
+        host,
+        base_port,
+        timeout,
+        command,
+    }
+}
+
+fn exit_code(error: &Error) -> i32 {
+    match error {
+        Error::NoNodes => 2,
+        Error::Unreachable(_) | Error::Io(_) => 3,
+        Error::Timeout => 4,
+        Error::Rejected(_) | Error::Server { .. } | Error::InvalidResponse(_) => 1,
+    }
+}
+
+// One-line summary of a node's dump for `watch`
+fn summary(dump: &Value) -> String {
+    let queue: Vec<&Value> = dump["local_queue"]
+        .as_array()
+        .map(|queue| queue.iter().map(|entry| &entry["value"]).collect())
+        .unwrap_or_default();
+    let pending = dump["pending_dequeues"]
+        .as_array()
+        .map_or(0, |pending| pending.len());
+    format!(
+        "clock={} members={} queue={:?} pending_dequeues={} locked={}",
+        dump["clock"], dump["members"], queue, pending, dump["locked"]
+    )
+}
+
+// Sums the `complexity` reports of several ranks. Each operation is invoked at exactly one
+// rank, so the summed `invoked` counts every operation once.
+fn complexity(config: &ClientConfig) -> Result<(), Error> {
+    let mut invoked: BTreeMap<String, u64> = BTreeMap::new();
+    let mut messages: BTreeMap<(String, String, &str), u64> = BTreeMap::new();
+    for &node in config.nodes.iter() {
+        let client = Client::new(ClientConfig::new(vec![node]).timeout(config.timeout));
+        let reports = client.admin("complexity")?;
+        for (op, report) in reports.as_object().into_iter().flatten() {
+            *invoked.entry(op.clone()).or_default() += report["invoked"].as_u64().unwrap_or(0);
+            for direction in ["sent", "received"] {
+                for (kind, count) in report[direction].as_object().into_iter().flatten() {
+                    *messages
+                        .entry((op.clone(), kind.clone(), direction))
+                        .or_default() += count.as_u64().unwrap_or(0);
+                }
+            }
+        }
+    }
+    for (op, &operations) in invoked.iter() {
+        let per_op = |count: u64| count as f64 / operations.max(1) as f64;
+        let sent: u64 = messages
+            .iter()
+            .filter(|((o, _, direction), _)| o == op && *direction == "sent")
+            .map(|(_, count)| count)
+            .sum();
+        println!(
+            "{}: {} operations, {:.1} messages sent per operation",
+            op,
+            operations,
+            per_op(sent)
+        );
+        for ((_, kind, direction), count) in messages.iter().filter(|((o, _, _), _)| o == op) {
+            println!(
+                "  {:<8} {:<8} {:>8} {:>8.1}/op",
+                kind,
+                direction,
+                count,
+                per_op(*count)
+            );
+        }
+    }
+    Ok(())
+}
+
+fn run(config: &ClientConfig, command: Command) -> Result<i32, Error> {
+    let client = Client::new(config.clone());
+    match command {
+        Command::Enqueue(value) => {
+            client.enqueue(value)?;
+            println!("enqueued {}", value);
+        }
+        Command::Dequeue => match client.dequeue()? {
+            Some(value) => println!("{}", value),
+            None => {
+                println!("empty");
+                return Ok(5);
+            }
+        },
+        Command::Status => {
+            let counters = client.admin("counters")?;
+            println!("{}", serde_json::to_string_pretty(&counters).unwrap());
+        }
+        Command::Dump => {
+            let dump = client.admin("dump")?;
+            println!("{}", serde_json::to_string_pretty(&dump).unwrap());
+        }
+        Command::Watch(interval) => {
+            let mut last = String::new();
+            loop {
+                let line = summary(&client.admin("dump")?);
+                if line != last {
+                    println!("{}", line);
+                    last = line;
+                }
+                thread::sleep(interval);
+            }
+        }
+        Command::Complexity(_) => complexity(config)?,
+    }
+    Ok(0)
+}
+
+fn main() {
+    let options = parse_args(env::args().skip(1));
+    let config = match options.addr {
+        Some(addr) => ClientConfig::new(vec![addr]),
+        None => {
+            let (port, ranks) = match options.command {
+                Command::Complexity(Some(ranks)) => (options.base_port, ranks),
+                _ => (
+                    options
+                        .base_port
+                        .checked_add(options.rank)
+                        .unwrap_or_else(|| usage_error("Port out of range")),
+                    1,
+                ),
+            };
+            ClientConfig::host(&options.host, port, ranks).unwrap_or_else(|e| {
+                eprintln!("Failed to resolve {}: {}", options.host, e);
+                exit(3)
+            })
+        }
+    };
+    let config = config.timeout(options.timeout);
+    match run(&config, options.command) {
+        Ok(code) => exit(code),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(exit_code(&e))
+        }
+    }
+}
@@ -0,0 +1,238 @@
+// Terminal dashboard that polls every rank and redraws the cluster state, e.g.
+//
+//     queuetop --ranks 4 --interval-ms 500
+//
+// Each rank shows its vector clock, queue contents, in-flight operations and throughput,
+// and every pending Dequeue lists the ranks whose responses are still missing, so the
+// process holding up progress stands out. Quit with Ctrl+C.
+use async_queue_client::{Client, ClientConfig, DEFAULT_BASE_PORT};
+use serde_json::Value;
+use std::env;
+use std::process::exit;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str =
+    "usage: queuetop [--ranks N] [--host HOST] [--base-port PORT] [--interval-ms MS]";
+
+// Longest queue prefix drawn per rank
+const QUEUE_PREVIEW: usize = 12;
+
+const CLEAR: &str = "\x1b[2J\x1b[H";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+struct Options {
+    ranks: u16,
+    host: String,
+    base_port: u16,
+    interval: Duration,
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    exit(2)
+}
+
+fn parse_value<T: std::str::FromStr>(arg: &str, value: Option<String>) -> T {
+    let value = value.unwrap_or_else(|| usage_error(&format!("Missing value for {}", arg)));
+    value
+        .parse()
+        .unwrap_or_else(|_| usage_error(&format!("Invalid value for {}: {}", arg, value)))
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Options {
+    let mut options = Options {
+        ranks: 4,
+        host: "127.0.0.1".to_string(),
+        base_port: DEFAULT_BASE_PORT,
+        interval: Duration::from_millis(1000),
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--ranks" => options.ranks = parse_value(&arg, args.next()),
+            "--host" => options.host = parse_value(&arg, args.next()),
+            "--base-port" => options.base_port = parse_value(&arg, args.next()),
+            "--interval-ms" => {
+                options.interval = Duration::from_millis(parse_value(&arg, args.next()))
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0)
+            }
+            _ => usage_error(&format!("Unknown argument {}", arg)),
+        }
+    }
+    options
+}
+
+// Completed operations at the previous poll, for throughput
+#[derive(Clone, Copy, Default)]
+struct Progress {
+    enqueues: u64,
+    dequeues: u64,
+    at: Option<Instant>,
+}
+
+fn completed(dump: &Value) -> (u64, u64) {
+    let counters = &dump["counters"];
+    (
+        counters["completed_enqueues"].as_u64().unwrap_or(0),
+        counters["completed_dequeues"].as_u64().unwrap_or(0),
+    )
+}
+
+fn clock(value: &Value) -> String {
+    let entries: Vec<String> = value
+        .as_array()
+        .map(|entries| entries.iter().map(|entry| entry.to_string()).collect())
+        .unwrap_or_default();
+    format!("[{}]", entries.join(" "))
+}
+
+// Members whose response is still missing from a Dequeue's confirmation list
+fn missing(list: &Value, members: &[u64]) -> Vec<u64> {
+    let responses = list["response_buffer"].as_array();
+    members
+        .iter()
+        .copied()
+        .filter(|&member| {
+            responses
+                .and_then(|responses| responses.get(member as usize))
+                .and_then(Value::as_i64)
+                != Some(1)
+        })
+        .collect()
+}
+
+fn draw_rank(out: &mut String, rank: usize, dump: &Value, progress: &mut Progress) {
+    let members: Vec<u64> = dump["members"]
+        .as_array()
+        .map(|members| members.iter().filter_map(Value::as_u64).collect())
+        .unwrap_or_default();
+    let (enqueues, dequeues) = completed(dump);
+    let now = Instant::now();
+    let throughput = match progress.at {
+        Some(at) => {
+            let elapsed = now.duration_since(at).as_secs_f64().max(f64::EPSILON);
+            format!(
+                "{:.1} enq/s {:.1} deq/s",
+                enqueues.saturating_sub(progress.enqueues) as f64 / elapsed,
+                dequeues.saturating_sub(progress.dequeues) as f64 / elapsed
+            )
+        }
+        None => "-".to_string(),
+    };
+    *progress = Progress {
+        enqueues,
+        dequeues,
+        at: Some(now),
+    };
+
+    let member = members.contains(&(rank as u64));
+    out.push_str(&format!(
+        "{}P{}{} {} clock={} done={}/{} {}\n",
+        BOLD,
+        rank,
+        RESET,
+        if member { "member" } else { "spare" },
+        clock(&dump["clock"]),
+        enqueues,
+        dequeues,
+        throughput
+    ));
+
+    let queue = dump["local_queue"].as_array().cloned().unwrap_or_default();
+    let preview: Vec<String> = queue
+        .iter()
+        .take(QUEUE_PREVIEW)
+        .map(|entry| format!("{}@P{}", entry["value"], entry["invoker"]))
+        .collect();
+    let more = queue.len().saturating_sub(QUEUE_PREVIEW);
+    out.push_str(&format!(
+        "  queue({}): {}{}\n",
+        queue.len(),
+        preview.join(" "),
+        if more > 0 {
+            format!(" ... +{}", more)
+        } else {
+            String::new()
+        }
+    ));
+
+    let mut in_flight = Vec::new();
+    if dump["enq_pending"].as_bool() == Some(true) {
+        in_flight.push(format!(
+            "Enqueue acked by {}/{}",
+            dump["enq_count"],
+            members.len()
+        ));
+    }
+    if dump["locked"].as_bool() == Some(true) {
+        in_flight.push("locked".to_string());
+    }
+    let outbound = dump["outbound"]
+        .as_array()
+        .map_or(0, |outbound| outbound.len());
+    if outbound > 0 {
+        in_flight.push(format!("{} messages waiting to send", outbound));
+    }
+    if !in_flight.is_empty() {
+        out.push_str(&format!("  {}{}{}\n", YELLOW, in_flight.join(", "), RESET));
+    }
+
+    for list in dump["pending_dequeues"].as_array().into_iter().flatten() {
+        if list["handled"].as_bool() == Some(true) {
+            continue;
+        }
+        let waiting_on: Vec<String> = missing(list, &members)
+            .iter()
+            .map(|rank| format!("P{}", rank))
+            .collect();
+        out.push_str(&format!(
+            "  Dequeue P{}{} waiting on {}{}{}\n",
+            list["invoker"],
+            clock(&list["ts"]),
+            RED,
+            waiting_on.join(" "),
+            RESET
+        ));
+    }
+}
+
+fn main() {
+    let options = parse_args(env::args().skip(1));
+    let config = ClientConfig::host(&options.host, options.base_port, options.ranks)
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to resolve {}: {}", options.host, e);
+            exit(3)
+        })
+        .timeout(options.interval.max(Duration::from_millis(500)));
+    let clients: Vec<Client> = config
+        .nodes
+        .iter()
+        .map(|&node| Client::new(ClientConfig::new(vec![node]).timeout(config.timeout)))
+        .collect();
+    let mut progress = vec![Progress::default(); clients.len()];
+
+    loop {
+        let mut out = String::from(CLEAR);
+        for (rank, client) in clients.iter().enumerate() {
+            match client.admin("dump") {
+                Ok(dump) => draw_rank(&mut out, rank, &dump, &mut progress[rank]),
+                Err(e) => {
+                    out.push_str(&format!(
+                        "{}P{}{} {}{}{}\n",
+                        BOLD, rank, RESET, RED, e, RESET
+                    ));
+                    progress[rank] = Progress::default();
+                }
+            }
+            out.push('\n');
+        }
+        print!("{}", out);
+        thread::sleep(options.interval);
+    }
+}
@@ -0,0 +1,98 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::{wire, ClientConfig, Error, Result};
+
+// Blocking client. It is `Sync`, so one instance can be shared between threads; calls to
+// the same node are serialized on that node's connection.
+pub struct Client {
+    config: ClientConfig,
+    connections: Vec<Mutex<Option<BufReader<TcpStream>>>>,
+    next_node: AtomicUsize,
+    next_id: AtomicU64,
+}
+
+impl Client {
+    pub fn new(config: ClientConfig) -> Self {
+        let connections = config.nodes.iter().map(|_| Mutex::new(None)).collect();
+        Client {
+            config,
+            connections,
+            next_node: AtomicUsize::new(0),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    pub fn enqueue(&self, value: i32) -> Result<()> {
+        self.call("enqueue", Some(value)).map(|_| ())
+    }
+
+    pub fn dequeue(&self) -> Result<Option<i32>> {
+        let (result, line) = self.call("dequeue", None)?;
+        wire::dequeued(&result, &line)
+    }
+
+    // Runs an admin command (dump, clock, queue, pending, outbound, counters, digest,
+    // history, complexity) and returns its JSON result
+    pub fn admin(&self, command: &str) -> Result<serde_json::Value> {
+        self.call(command, None).map(|(result, _)| result)
+    }
+
+    fn connect(&self, node: usize) -> io::Result<BufReader<TcpStream>> {
+        let stream = TcpStream::connect_timeout(&self.config.nodes[node], self.config.timeout)?;
+        stream.set_read_timeout(Some(self.config.timeout))?;
+        stream.set_write_timeout(Some(self.config.timeout))?;
+        stream.set_nodelay(true)?;
+        Ok(BufReader::new(stream))
+    }
+
+    // Sends the request to the next node that accepts a connection. Once a request has been
+    // written it is never retried elsewhere, since the operation may already have run.
+    fn call(&self, op: &str, value: Option<i32>) -> Result<(serde_json::Value, String)> {
+        let nodes = self.config.nodes.len();
+        if nodes == 0 {
+            return Err(Error::NoNodes);
+        }
+        let first = self.next_node.fetch_add(1, Ordering::Relaxed);
+        let mut last_error = None;
+        for attempt in 0..nodes {
+            let node = (first + attempt) % nodes;
+            let mut slot = self.connections[node].lock().unwrap();
+            if slot.is_none() {
+                match self.connect(node) {
+                    Ok(connection) => *slot = Some(connection),
+                    Err(e) => {
+                        last_error = Some(e);
+                        continue;
+                    }
+                }
+            }
+            let connection = slot.as_mut().unwrap();
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            let result = exchange(connection, &wire::request(id, op, value)).and_then(|line| {
+                let result = wire::response(&line, id)?;
+                Ok((result, line))
+            });
+            // A connection that failed or timed out may still deliver a stale response
+            if matches!(
+                result,
+                Err(Error::Io(_) | Error::Timeout | Error::InvalidResponse(_))
+            ) {
+                *slot = None;
+            }
+            return result;
+        }
+        Err(Error::Unreachable(last_error.unwrap()))
+    }
+}
+
+fn exchange(connection: &mut BufReader<TcpStream>, request: &str) -> Result<String> {
+    connection.get_mut().write_all(request.as_bytes())?;
+    let mut line = String::new();
+    if connection.read_line(&mut line)? == 0 {
+        return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+    Ok(line)
+}
@@ -0,0 +1,123 @@
+// Client for the replicated queue, speaking version 1 of the JSON line protocol on each
+// node's client port (8000 + rank by default).
+//
+//     let client = Client::new(ClientConfig::local(4));
+//     client.enqueue(5)?;
+//     assert_eq!(client.dequeue()?, Some(5));
+//
+// Operations are spread round-robin over the configured nodes and each node keeps one open
+// connection that is reused between calls. With the `async` feature, `AsyncClient` offers
+// the same API on tokio.
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+mod blocking;
+#[cfg(feature = "async")]
+mod nonblocking;
+mod wire;
+
+pub use blocking::Client;
+#[cfg(feature = "async")]
+pub use nonblocking::AsyncClient;
+
+pub const DEFAULT_BASE_PORT: u16 = 8000;
+// Longer than a node's own 30 s client timeout, so a slow operation is reported by the node
+// as a timeout response rather than cut off here while the node is still answering
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(35);
+
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    pub nodes: Vec<SocketAddr>,
+    // Applies to connecting and to waiting for each response
+    pub timeout: Duration,
+}
+
+impl ClientConfig {
+    pub fn new(nodes: Vec<SocketAddr>) -> Self {
+        ClientConfig {
+            nodes,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    // Ranks 0..ranks on this machine at the default ports
+    pub fn local(ranks: u16) -> Self {
+        Self::new(
+            (0..ranks)
+                .map(|rank| SocketAddr::from(([127, 0, 0, 1], DEFAULT_BASE_PORT + rank)))
+                .collect(),
+        )
+    }
+
+    // Ranks 0..ranks of `host`, rank r listening on `base_port + r`
+    pub fn host(host: &str, base_port: u16, ranks: u16) -> io::Result<Self> {
+        let mut nodes = Vec::new();
+        for rank in 0..ranks {
+            let addr = (host, base_port + rank)
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, host.to_string()))?;
+            nodes.push(addr);
+        }
+        Ok(Self::new(nodes))
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    // The configuration lists no nodes
+    NoNodes,
+    // Every node refused the connection; holds the last failure
+    Unreachable(io::Error),
+    Io(io::Error),
+    // No response within the configured timeout, or the node reported one. The operation may
+    // still take effect.
+    Timeout,
+    // The node declined the operation, e.g. because it is not a member
+    Rejected(String),
+    // Any other error response from the node
+    Server { code: String, message: String },
+    // The node sent something that is not a version 1 response
+    InvalidResponse(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoNodes => write!(f, "no nodes configured"),
+            Error::Unreachable(e) => write!(f, "no node reachable: {}", e),
+            Error::Io(e) => write!(f, "connection failed: {}", e),
+            Error::Timeout => write!(f, "operation timed out"),
+            Error::Rejected(reason) => write!(f, "operation rejected: {}", reason),
+            Error::Server { code, message } => write!(f, "{}: {}", code, message),
+            Error::InvalidResponse(line) => write!(f, "invalid response: {}", line),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Unreachable(e) | Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
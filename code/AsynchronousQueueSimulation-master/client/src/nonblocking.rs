@@ -0,0 +1,101 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+
+use crate::{wire, ClientConfig, Error, Result};
+
+// Tokio counterpart of `Client`, with the same pooling and retry behaviour
+pub struct AsyncClient {
+    config: ClientConfig,
+    connections: Vec<Mutex<Option<BufReader<TcpStream>>>>,
+    next_node: AtomicUsize,
+    next_id: AtomicU64,
+}
+
+impl AsyncClient {
+    pub fn new(config: ClientConfig) -> Self {
+        let connections = config.nodes.iter().map(|_| Mutex::new(None)).collect();
+        AsyncClient {
+            config,
+            connections,
+            next_node: AtomicUsize::new(0),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn enqueue(&self, value: i32) -> Result<()> {
+        self.call("enqueue", Some(value)).await.map(|_| ())
+    }
+
+    pub async fn dequeue(&self) -> Result<Option<i32>> {
+        let (result, line) = self.call("dequeue", None).await?;
+        wire::dequeued(&result, &line)
+    }
+
+    pub async fn admin(&self, command: &str) -> Result<serde_json::Value> {
+        self.call(command, None).await.map(|(result, _)| result)
+    }
+
+    async fn connect(&self, node: usize) -> io::Result<BufReader<TcpStream>> {
+        let stream = timeout(
+            self.config.timeout,
+            TcpStream::connect(self.config.nodes[node]),
+        )
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        stream.set_nodelay(true)?;
+        Ok(BufReader::new(stream))
+    }
+
+    async fn call(&self, op: &str, value: Option<i32>) -> Result<(serde_json::Value, String)> {
+        let nodes = self.config.nodes.len();
+        if nodes == 0 {
+            return Err(Error::NoNodes);
+        }
+        let first = self.next_node.fetch_add(1, Ordering::Relaxed);
+        let mut last_error = None;
+        for attempt in 0..nodes {
+            let node = (first + attempt) % nodes;
+            let mut slot = self.connections[node].lock().await;
+            if slot.is_none() {
+                match self.connect(node).await {
+                    Ok(connection) => *slot = Some(connection),
+                    Err(e) => {
+                        last_error = Some(e);
+                        continue;
+                    }
+                }
+            }
+            let connection = slot.as_mut().unwrap();
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            let request = wire::request(id, op, value);
+            let result = match timeout(self.config.timeout, exchange(connection, &request)).await {
+                Ok(result) => result.and_then(|line| {
+                    let result = wire::response(&line, id)?;
+                    Ok((result, line))
+                }),
+                Err(_) => Err(Error::Timeout),
+            };
+            if matches!(
+                result,
+                Err(Error::Io(_) | Error::Timeout | Error::InvalidResponse(_))
+            ) {
+                *slot = None;
+            }
+            return result;
+        }
+        Err(Error::Unreachable(last_error.unwrap()))
+    }
+}
+
+async fn exchange(connection: &mut BufReader<TcpStream>, request: &str) -> Result<String> {
+    connection.get_mut().write_all(request.as_bytes()).await?;
+    let mut line = String::new();
+    if connection.read_line(&mut line).await? == 0 {
+        return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+    Ok(line)
+}
@@ -0,0 +1,133 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{Error, Result};
+
+const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Deserialize)]
+struct Response {
+    v: u32,
+    #[serde(default)]
+    id: Value,
+    ok: bool,
+    #[serde(default)]
+    result: Value,
+    error: Option<ErrorBody>,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    code: String,
+    message: String,
+}
+
+// Request line, including the trailing newline
+pub fn request(id: u64, op: &str, value: Option<i32>) -> String {
+    let mut request = json!({ "v": PROTOCOL_VERSION, "id": id, "op": op });
+    if let Some(value) = value {
+        request["value"] = json!(value);
+    }
+    format!("{}\n", request)
+}
+
+// Result of a response line, checked against the id of its request
+pub fn response(line: &str, id: u64) -> Result<Value> {
+    let invalid = || Error::InvalidResponse(line.trim().to_string());
+    let response: Response = serde_json::from_str(line).map_err(|_| invalid())?;
+    if response.v != PROTOCOL_VERSION || response.id != json!(id) {
+        return Err(invalid());
+    }
+    if response.ok {
+        return Ok(response.result);
+    }
+    let error = response.error.ok_or_else(invalid)?;
+    Err(match error.code.as_str() {
+        "timeout" => Error::Timeout,
+        "rejected" => Error::Rejected(error.message),
+        _ => Error::Server {
+            code: error.code,
+            message: error.message,
+        },
+    })
+}
+
+// Value removed by a Dequeue, None when the queue was empty
+pub fn dequeued(result: &Value, line: &str) -> Result<Option<i32>> {
+    match &result["value"] {
+        Value::Null => Ok(None),
+        value => value
+            .as_i64()
+            .and_then(|value| i32::try_from(value).ok())
+            .map(Some)
+            .ok_or_else(|| Error::InvalidResponse(line.trim().to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_one_versioned_line() {
+        let line = request(7, "enqueue", Some(5));
+        assert!(line.ends_with('\n') && !line.trim_end().contains('\n'));
+        let parsed: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            parsed,
+            json!({ "v": PROTOCOL_VERSION, "id": 7, "op": "enqueue", "value": 5 })
+        );
+
+        let parsed: Value = serde_json::from_str(&request(8, "dequeue", None)).unwrap();
+        assert!(parsed.get("value").is_none());
+    }
+
+    #[test]
+    fn results_are_returned_for_the_matching_id() {
+        let line = r#"{"v":1,"id":7,"ok":true,"result":{"value":5}}"#;
+        assert_eq!(response(line, 7).unwrap(), json!({ "value": 5 }));
+        assert!(matches!(response(line, 8), Err(Error::InvalidResponse(_))));
+        assert!(matches!(
+            response(r#"{"v":2,"id":7,"ok":true}"#, 7),
+            Err(Error::InvalidResponse(_))
+        ));
+        assert!(matches!(
+            response("not json", 7),
+            Err(Error::InvalidResponse(_))
+        ));
+    }
+
+    #[test]
+    fn error_codes_map_to_errors() {
+        let error = |code: &str| {
+            let line = format!(
+                r#"{{"v":1,"id":1,"ok":false,"error":{{"code":"{}","message":"m"}}}}"#,
+                code
+            );
+            response(&line, 1).unwrap_err()
+        };
+        assert!(matches!(error("timeout"), Error::Timeout));
+        assert!(matches!(error("rejected"), Error::Rejected(message) if message == "m"));
+        assert!(matches!(
+            error("unknown_op"),
+            Error::Server { code, .. } if code == "unknown_op"
+        ));
+        assert!(matches!(
+            response(r#"{"v":1,"id":1,"ok":false}"#, 1),
+            Err(Error::InvalidResponse(_))
+        ));
+    }
+
+    #[test]
+    fn dequeued_values_decode() {
+        assert_eq!(dequeued(&json!({ "value": 5 }), "").unwrap(), Some(5));
+        assert_eq!(dequeued(&json!({ "value": null }), "").unwrap(), None);
+        assert_eq!(dequeued(&json!({}), "").unwrap(), None);
+        for value in [json!("5"), json!(1.5), json!(i64::from(i32::MAX) + 1)] {
+            assert!(matches!(
+                dequeued(&json!({ "value": value }), "line"),
+                Err(Error::InvalidResponse(line)) if line == "line"
+            ));
+        }
+    }
+}
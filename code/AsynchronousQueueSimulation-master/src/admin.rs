@@ -0,0 +1,319 @@
+use mpi::Rank;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+
+use crate::complexity::MessageComplexity;
+use crate::message_payload::MessagePayload;
+use crate::metrics::Metrics;
+use crate::process_data::ProcessData;
+
+// Admin commands are single words typed on the client port. The client thread forwards them
+// to the MPI thread, which owns the node state, and writes back its one-line JSON reply.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AdminCommand {
+    Dump,
+    Clock,
+    Queue,
+    Pending,
+    Outbound,
+    Counters,
+    Digest,
+    History,
+    Complexity,
+    // Prometheus text, only requested by the metrics server
+    Metrics,
+}
+
+impl FromStr for AdminCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dump" => Ok(AdminCommand::Dump),
+            "clock" => Ok(AdminCommand::Clock),
+            "queue" => Ok(AdminCommand::Queue),
+            "pending" => Ok(AdminCommand::Pending),
+            "outbound" => Ok(AdminCommand::Outbound),
+            "counters" => Ok(AdminCommand::Counters),
+            "digest" => Ok(AdminCommand::Digest),
+            "history" => Ok(AdminCommand::History),
+            "complexity" => Ok(AdminCommand::Complexity),
+            _ => Err(format!("Unknown admin command: {}", s)),
+        }
+    }
+}
+
+pub struct AdminRequest {
+    pub command: AdminCommand,
+    pub reply: Sender<String>,
+}
+
+// Message and operation counts kept by the MPI thread, keyed by message kind
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct OperationCounters {
+    pub received: BTreeMap<&'static str, u64>,
+    pub sent: BTreeMap<&'static str, u64>,
+    // MPI sends; with batching one carries several messages
+    pub frames_sent: u64,
+    // Dequeue hash chains that differed from another member's at the same point
+    pub digest_divergences: u64,
+    // On the auditor: Dequeues every executing replica reported, and reports that disagreed
+    pub audited_dequeues: u64,
+    pub audit_divergences: u64,
+}
+
+impl OperationCounters {
+    pub fn on_received(&mut self, message: &MessagePayload) {
+        *self.received.entry(message.kind()).or_default() += 1;
+    }
+
+    pub fn on_sent(&mut self, message: &MessagePayload) {
+        *self.sent.entry(message.kind()).or_default() += 1;
+    }
+}
+
+// The FIFO queue has no per-process labels (those belong to the relaxed algorithm), so
+// each element is reported with the process that enqueued it
+fn queue_json(process_data: &ProcessData) -> serde_json::Value {
+    process_data
+        .local_queue
+        .iter()
+        .map(|(value, invoker, ts)| json!({ "value": value, "invoker": invoker, "ts": ts }))
+        .collect()
+}
+
+pub fn answer(
+    command: AdminCommand,
+    rank: Rank,
+    process_data: &ProcessData,
+    outbound: &[MessagePayload],
+    counters: &OperationCounters,
+    complexity: &MessageComplexity,
+    metrics: &Metrics,
+) -> String {
+    let counters_json = || {
+        json!({
+            "completed_enqueues": process_data.completed_enqueues,
+            "completed_dequeues": process_data.completed_dequeues,
+            "local_queue_len": process_data.local_queue.len(),
+            "messages": counters,
+        })
+    };
+    let reply = match command {
+        AdminCommand::Dump => json!({
+            "rank": rank,
+            "clock": process_data.timestamp,
+            "members": process_data.membership.ranks(),
+            "locked": process_data.locked,
+            "enq_pending": process_data.enq_pending,
+            "enq_count": process_data.enq_count,
+            "local_queue": queue_json(process_data),
+            "pending_dequeues": process_data.pending_dequeues,
+            "outbound": outbound,
+            "counters": counters_json(),
+            "digest": process_data.digest,
+        }),
+        AdminCommand::Clock => json!(process_data.timestamp),
+        AdminCommand::Queue => queue_json(process_data),
+        AdminCommand::Pending => json!(process_data.pending_dequeues),
+        AdminCommand::Outbound => json!(outbound),
+        AdminCommand::Counters => counters_json(),
+        AdminCommand::Digest => {
+            let mut digest = json!(process_data.digest);
+            digest["divergences"] = json!(counters.digest_divergences);
+            digest
+        }
+        AdminCommand::History => json!(process_data.message_history),
+        AdminCommand::Complexity => json!(complexity.report()),
+        // Already text, not JSON
+        AdminCommand::Metrics => {
+            return metrics.render(rank, process_data, counters, complexity);
+        }
+    };
+    reply.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::message_payload::VectorClock;
+    use serde_json::Value;
+
+    const COMMANDS: [&str; 9] = [
+        "dump",
+        "clock",
+        "queue",
+        "pending",
+        "outbound",
+        "counters",
+        "digest",
+        "history",
+        "complexity",
+    ];
+
+    fn vc(entries: &[i32]) -> VectorClock {
+        VectorClock::try_from(entries.to_vec()).unwrap()
+    }
+
+    // Rank 0 of two after applying rank 1's Enqueue of 5 and receiving its Dequeue request
+    struct Node {
+        process_data: ProcessData,
+        outbound: Vec<MessagePayload>,
+        counters: OperationCounters,
+        complexity: MessageComplexity,
+        metrics: Metrics,
+    }
+
+    impl Node {
+        fn new() -> Self {
+            let mut process_data = ProcessData::new(0, 2, &Config::default());
+            let mut counters = OperationCounters::default();
+            let mut complexity = MessageComplexity::new(0);
+            let mut outbound = Vec::new();
+            for message in [
+                MessagePayload::new(1, 5, 1, 1, 0, vc(&[0, 1])),
+                MessagePayload::new(4, 0, 1, 1, 0, vc(&[0, 2])),
+            ] {
+                counters.on_received(&message);
+                complexity.on_received(&message);
+                process_data.message_history.push(message);
+                outbound.extend(process_data.execute_locally(message));
+            }
+            complexity.end_step();
+            counters.digest_divergences = 1;
+            Node {
+                process_data,
+                outbound,
+                counters,
+                complexity,
+                metrics: Metrics::default(),
+            }
+        }
+
+        fn ask(&self, command: &str) -> Value {
+            let reply = answer(
+                command.parse().unwrap(),
+                0,
+                &self.process_data,
+                &self.outbound,
+                &self.counters,
+                &self.complexity,
+                &self.metrics,
+            );
+            serde_json::from_str(&reply).unwrap()
+        }
+    }
+
+    #[test]
+    fn parses_every_command_name() {
+        for name in COMMANDS {
+            assert_eq!(
+                format!("{:?}", name.parse::<AdminCommand>().unwrap()).to_lowercase(),
+                name
+            );
+        }
+        // Only the metrics server asks for these
+        assert!("metrics".parse::<AdminCommand>().is_err());
+        assert!("Dump".parse::<AdminCommand>().is_err());
+    }
+
+    #[test]
+    fn state_commands_report_the_replica() {
+        let node = Node::new();
+        assert_eq!(node.ask("clock"), json!([0, 2]));
+        assert_eq!(
+            node.ask("queue"),
+            json!([{ "value": 5, "invoker": 1, "ts": [0, 1] }])
+        );
+        assert_eq!(
+            node.ask("pending"),
+            json!([{ "response_buffer": [0, 0], "ts": [0, 2], "invoker": 1, "handled": false }])
+        );
+        let history = node.ask("history");
+        let codes: Vec<&Value> = history
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|m| &m["message"])
+            .collect();
+        assert_eq!(codes, vec![&json!(1), &json!(4)]);
+    }
+
+    #[test]
+    fn outbound_lists_unsent_messages() {
+        let node = Node::new();
+        let outbound = node.ask("outbound");
+        let outbound = outbound.as_array().unwrap();
+        assert_eq!(outbound.len(), node.outbound.len());
+        // The EnqAck back to rank 1 is among them
+        assert!(outbound
+            .iter()
+            .any(|m| m["message"] == json!(2) && m["receiver"] == json!(1)));
+    }
+
+    #[test]
+    fn counters_and_digest_include_the_message_counts() {
+        let node = Node::new();
+        let counters = node.ask("counters");
+        assert_eq!(counters["local_queue_len"], json!(1));
+        assert_eq!(counters["completed_enqueues"], json!(0));
+        assert_eq!(counters["completed_dequeues"], json!(0));
+        assert_eq!(
+            counters["messages"]["received"],
+            json!({ "EnqReq": 1, "DeqReq": 1 })
+        );
+        assert_eq!(counters["messages"]["digest_divergences"], json!(1));
+        assert_eq!(counters["messages"]["audited_dequeues"], json!(0));
+
+        let digest = node.ask("digest");
+        assert_eq!(digest["enqueue_count"], json!(1));
+        assert_eq!(digest["dequeue_count"], json!(0));
+        assert_eq!(digest["divergences"], json!(1));
+        assert!(digest.get("checkpoints").is_none());
+    }
+
+    #[test]
+    fn complexity_reports_folded_operations() {
+        let node = Node::new();
+        let complexity = node.ask("complexity");
+        assert_eq!(complexity["enqueue"]["operations"], json!(1));
+        assert_eq!(complexity["enqueue"]["received"], json!({ "EnqReq": 1 }));
+        // The Dequeue has not executed here yet
+        assert!(complexity.get("dequeue").is_none());
+    }
+
+    #[test]
+    fn dump_combines_the_other_views() {
+        let node = Node::new();
+        let dump = node.ask("dump");
+        assert_eq!(dump["rank"], json!(0));
+        assert_eq!(dump["members"], json!([0, 1]));
+        assert_eq!(dump["locked"], json!(false));
+        assert_eq!(dump["clock"], node.ask("clock"));
+        assert_eq!(dump["local_queue"], node.ask("queue"));
+        assert_eq!(dump["pending_dequeues"], node.ask("pending"));
+        assert_eq!(dump["outbound"], node.ask("outbound"));
+        assert_eq!(dump["counters"], node.ask("counters"));
+        assert_eq!(dump["digest"]["enqueue_count"], json!(1));
+    }
+
+    #[test]
+    fn metrics_are_prometheus_text() {
+        let node = Node::new();
+        let text = answer(
+            AdminCommand::Metrics,
+            0,
+            &node.process_data,
+            &node.outbound,
+            &node.counters,
+            &node.complexity,
+            &node.metrics,
+        );
+        assert!(serde_json::from_str::<Value>(&text).is_err());
+        assert!(text.contains("async_queue_digest_divergences_total{rank=\"0\"} 1\n"));
+    }
+}
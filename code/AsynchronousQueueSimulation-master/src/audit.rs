@@ -0,0 +1,161 @@
+use mpi::Rank;
+use std::collections::BTreeMap;
+use tracing::error;
+
+use crate::message_payload::{MessagePayload, VectorClock};
+
+// Report (20) sent to the auditor for every Dequeue a replica executes: the Dequeue's
+// timestamp and invoker, and the value the replica removed (-1 for empty)
+pub fn reports(
+    executed: Vec<(VectorClock, Rank, i32)>,
+    rank: Rank,
+    auditor: Rank,
+) -> Vec<MessagePayload> {
+    executed
+        .into_iter()
+        .map(|(ts, invoker, value)| MessagePayload::new(20, value, invoker, rank, auditor, ts))
+        .collect()
+}
+
+// Checks the core lemma of the algorithm at runtime: every replica removes the same value
+// for a given Dequeue timestamp. Runs on one designated process, which collects a report
+// from each member for every Dequeue and flags any disagreement as soon as it is seen.
+pub struct DequeueAuditor {
+    // Reports per Dequeue timestamp not yet confirmed by every member
+    reports: BTreeMap<VectorClock, Vec<(Rank, i32)>>,
+    // Values of the latest confirmed Dequeues, so a report arriving after its Dequeue was
+    // confirmed (e.g. from a replica suspected meanwhile) is still checked but opens nothing
+    closed: BTreeMap<VectorClock, (Rank, i32)>,
+    pub audited: u64,
+    pub divergences: u64,
+}
+
+impl DequeueAuditor {
+    // Confirmed Dequeues older than this many are forgotten
+    const HISTORY: usize = 1024;
+
+    pub fn new() -> Self {
+        DequeueAuditor {
+            reports: BTreeMap::new(),
+            closed: BTreeMap::new(),
+            audited: 0,
+            divergences: 0,
+        }
+    }
+
+    // `expected_reports` is the number of replicas that execute each Dequeue
+    pub fn on_report(&mut self, report: &MessagePayload, expected_reports: i32) {
+        if let Some(&first) = self.closed.get(&report.time_stamp) {
+            self.check(first, report);
+            return;
+        }
+        if self.closed.len() >= Self::HISTORY
+            && self
+                .closed
+                .first_key_value()
+                .is_some_and(|(oldest, _)| report.time_stamp < *oldest)
+        {
+            return;
+        }
+        let entry = self.reports.entry(report.time_stamp).or_default();
+        let first = entry.first().copied();
+        entry.push((report.sender, report.value));
+        let confirmed = entry.len() as i32 >= expected_reports;
+        if let Some(first) = first {
+            self.check(first, report);
+        }
+        if confirmed {
+            self.reports.remove(&report.time_stamp);
+            self.audited += 1;
+            let first = first.unwrap_or((report.sender, report.value));
+            self.closed.insert(report.time_stamp, first);
+            while self.closed.len() > Self::HISTORY {
+                self.closed.pop_first();
+            }
+        }
+    }
+
+    fn check(&mut self, (first_rank, first_value): (Rank, i32), report: &MessagePayload) {
+        if first_value != report.value {
+            self.divergences += 1;
+            error!(
+                dequeue_ts = %report.time_stamp,
+                invoker = report.invoker,
+                "AUDIT FAILURE: Dequeue removed {} at process {} but {} at process {}",
+                first_value,
+                first_rank,
+                report.value,
+                report.sender
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(sender: Rank, ts: &[i32], value: i32) -> MessagePayload {
+        let ts = VectorClock::try_from(ts.to_vec()).unwrap();
+        MessagePayload::new(20, value, 0, sender, 0, ts)
+    }
+
+    #[test]
+    fn reports_are_built_per_executed_dequeue() {
+        let ts = VectorClock::try_from(vec![1, 2]).unwrap();
+        let sent = reports(vec![(ts, 1, 5), (ts, 1, -1)], 2, 0);
+        assert_eq!(sent.len(), 2);
+        assert!(sent
+            .iter()
+            .all(|m| m.message == 20 && m.sender == 2 && m.receiver == 0 && m.invoker == 1));
+        assert_eq!(sent[1].value, -1);
+    }
+
+    #[test]
+    fn agreeing_replicas_confirm_a_dequeue() {
+        let mut auditor = DequeueAuditor::new();
+        for sender in 0..3 {
+            auditor.on_report(&report(sender, &[1, 0, 0], 5), 3);
+        }
+        assert_eq!(auditor.audited, 1);
+        assert_eq!(auditor.divergences, 0);
+        assert!(auditor.reports.is_empty());
+    }
+
+    #[test]
+    fn each_disagreeing_report_is_a_divergence() {
+        let mut auditor = DequeueAuditor::new();
+        auditor.on_report(&report(0, &[1, 0, 0], 5), 3);
+        auditor.on_report(&report(1, &[1, 0, 0], 6), 3);
+        auditor.on_report(&report(2, &[1, 0, 0], 7), 3);
+        assert_eq!(auditor.audited, 1);
+        assert_eq!(auditor.divergences, 2);
+    }
+
+    #[test]
+    fn late_reports_are_checked_without_reopening_the_dequeue() {
+        let mut auditor = DequeueAuditor::new();
+        // Rank 2 is suspected, so two reports confirm the Dequeue
+        auditor.on_report(&report(0, &[1, 0, 0], 5), 2);
+        auditor.on_report(&report(1, &[1, 0, 0], 5), 2);
+        auditor.on_report(&report(2, &[1, 0, 0], 5), 2);
+        assert_eq!(auditor.audited, 1);
+        assert!(auditor.reports.is_empty());
+        // A repeated report, now disagreeing
+        auditor.on_report(&report(2, &[1, 0, 0], 6), 2);
+        assert_eq!(auditor.divergences, 1);
+        assert!(auditor.reports.is_empty());
+    }
+
+    #[test]
+    fn confirmed_dequeues_are_remembered_for_a_bounded_history() {
+        let mut auditor = DequeueAuditor::new();
+        for i in 0..DequeueAuditor::HISTORY as i32 + 10 {
+            auditor.on_report(&report(0, &[i + 1, 0], 5), 1);
+        }
+        assert_eq!(auditor.closed.len(), DequeueAuditor::HISTORY);
+        // Older than anything remembered: dropped rather than left open
+        auditor.on_report(&report(1, &[1, 0], 5), 2);
+        assert!(auditor.reports.is_empty());
+    }
+}
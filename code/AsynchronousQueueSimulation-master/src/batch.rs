@@ -0,0 +1,317 @@
+use mpi::datatype::Equivalence;
+use mpi::point_to_point::Destination;
+use mpi::request::{Request, StaticScope};
+use mpi::topology::Communicator;
+use mpi::{Rank, Tag};
+use std::collections::{BTreeMap, VecDeque};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::message_payload::{MessagePayload, VectorClock};
+use crate::shiviz::CLOCK_TAG;
+
+// Outbound messages are coalesced into frames, one per destination, and each frame travels
+// as a single MPI send. Messages keep their order within a frame, and frames to the same
+// destination leave in order, so the per-channel FIFO order the algorithm relies on holds.
+// Receivers post a buffer for the largest `--batch-max` of any rank, agreed on at startup.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    // Every message is sent on its own as soon as the send rule allows it
+    #[default]
+    Immediate,
+    // Messages to the same destination produced in one step share a frame
+    Step,
+    // A frame is held for up to this long for more messages, unless it fills up first
+    Delay(Duration),
+}
+
+impl FromStr for FlushPolicy {
+    type Err = String;
+
+    // `immediate`, `step` or `delay:<ms>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "immediate" => Ok(FlushPolicy::Immediate),
+            None if s == "step" => Ok(FlushPolicy::Step),
+            Some(("delay", ms)) => ms
+                .parse()
+                .map(|ms| FlushPolicy::Delay(Duration::from_millis(ms)))
+                .map_err(|_| format!("Invalid batch delay: {}", ms)),
+            _ => Err(format!("Unknown flush policy: {}", s)),
+        }
+    }
+}
+
+struct Frame {
+    messages: Vec<MessagePayload>,
+    opened: Instant,
+}
+
+pub struct Outbox {
+    policy: FlushPolicy,
+    max: usize,
+    frames: BTreeMap<Rank, Frame>,
+}
+
+impl Outbox {
+    pub fn new(policy: FlushPolicy, max: usize) -> Self {
+        Outbox {
+            policy,
+            max: if policy == FlushPolicy::Immediate {
+                1
+            } else {
+                max
+            },
+            frames: BTreeMap::new(),
+        }
+    }
+
+    // Adds a message to the frame for its receiver, returning the frame if it is now full
+    pub fn push(&mut self, message: MessagePayload) -> Option<(Rank, Vec<MessagePayload>)> {
+        let frame = self
+            .frames
+            .entry(message.receiver)
+            .or_insert_with(|| Frame {
+                messages: Vec::new(),
+                opened: Instant::now(),
+            });
+        frame.messages.push(message);
+        if frame.messages.len() < self.max {
+            return None;
+        }
+        self.frames
+            .remove(&message.receiver)
+            .map(|frame| (message.receiver, frame.messages))
+    }
+
+    // Frames the policy sends at the end of a step
+    pub fn due(&mut self) -> Vec<(Rank, Vec<MessagePayload>)> {
+        let now = Instant::now();
+        let due: Vec<Rank> = self
+            .frames
+            .iter()
+            .filter(|(_, frame)| match self.policy {
+                FlushPolicy::Delay(delay) => now.duration_since(frame.opened) >= delay,
+                _ => true,
+            })
+            .map(|(&receiver, _)| receiver)
+            .collect();
+        due.into_iter()
+            .filter_map(|receiver| {
+                self.frames
+                    .remove(&receiver)
+                    .map(|frame| (receiver, frame.messages))
+            })
+            .collect()
+    }
+
+    // Every held frame, regardless of the policy
+    pub fn drain(&mut self) -> Vec<(Rank, Vec<MessagePayload>)> {
+        std::mem::take(&mut self.frames)
+            .into_iter()
+            .map(|(receiver, frame)| (receiver, frame.messages))
+            .collect()
+    }
+
+    // Messages held in frames that have not been sent yet
+    pub fn pending(&self) -> impl Iterator<Item = &MessagePayload> {
+        self.frames.values().flat_map(|frame| frame.messages.iter())
+    }
+}
+
+// Frames to one destination that may be handed to MPI and not yet completed
+const FRAMES_IN_FLIGHT: usize = 16;
+
+// A nonblocking send and the buffer it reads from. The buffer is leaked to give the send
+// the 'static borrow it needs and reclaimed once the send has completed.
+struct InFlight<T: 'static> {
+    request: Request<'static, [T]>,
+    buffer: *mut [T],
+}
+
+impl<T: Equivalence + 'static> InFlight<T> {
+    fn start<D: Destination>(destination: &D, data: Vec<T>, tag: Tag) -> Self {
+        let buffer: &'static [T] = Box::leak(data.into_boxed_slice());
+        InFlight {
+            request: destination.immediate_send_with_tag(StaticScope, buffer, tag),
+            buffer: buffer as *const [T] as *mut [T],
+        }
+    }
+
+    // The send, if it has not completed yet
+    fn test(self) -> Option<Self> {
+        match self.request.test() {
+            Ok(_) => {
+                // SAFETY: the send has completed, so MPI no longer reads the buffer, and
+                // `test` consumed the request that borrowed it
+                drop(unsafe { Box::from_raw(self.buffer) });
+                None
+            }
+            Err(request) => Some(InFlight {
+                request,
+                buffer: self.buffer,
+            }),
+        }
+    }
+}
+
+// A frame and, when tracing, the ShiViz clocks sent after it
+type Queued = (Vec<MessagePayload>, Option<Vec<VectorClock>>);
+type Started = (
+    Option<InFlight<MessagePayload>>,
+    Option<InFlight<VectorClock>>,
+);
+
+// Frames leave as nonblocking standard sends, so a rank never blocks in a send while the
+// peer is blocked sending back to it. Once `FRAMES_IN_FLIGHT` frames to a destination are
+// outstanding, later ones wait here, in order, until earlier sends complete: a slow or
+// suspected peer holds back its own traffic instead of exhausting a send buffer.
+#[derive(Default)]
+pub struct Sends {
+    in_flight: BTreeMap<Rank, Vec<Started>>,
+    held: BTreeMap<Rank, VecDeque<Queued>>,
+}
+
+impl Sends {
+    pub fn send<C: Communicator>(
+        &mut self,
+        world: &C,
+        receiver: Rank,
+        frame: Vec<MessagePayload>,
+        clocks: Option<Vec<VectorClock>>,
+    ) {
+        self.held
+            .entry(receiver)
+            .or_default()
+            .push_back((frame, clocks));
+        self.start_held(world, receiver);
+    }
+
+    fn start_held<C: Communicator>(&mut self, world: &C, receiver: Rank) {
+        let in_flight = self.in_flight.entry(receiver).or_default();
+        let Some(held) = self.held.get_mut(&receiver) else {
+            return;
+        };
+        while in_flight.len() < FRAMES_IN_FLIGHT {
+            let Some((frame, clocks)) = held.pop_front() else {
+                break;
+            };
+            let process = world.process_at_rank(receiver);
+            let frame = InFlight::start(&process, frame, Tag::default());
+            let clocks = clocks.map(|clocks| InFlight::start(&process, clocks, CLOCK_TAG));
+            in_flight.push((Some(frame), clocks));
+        }
+        if held.is_empty() {
+            self.held.remove(&receiver);
+        }
+    }
+
+    // Reclaims completed sends and starts held frames in their place
+    pub fn progress<C: Communicator>(&mut self, world: &C) {
+        for in_flight in self.in_flight.values_mut() {
+            *in_flight = std::mem::take(in_flight)
+                .into_iter()
+                .map(|(frame, clocks)| {
+                    (
+                        frame.and_then(InFlight::test),
+                        clocks.and_then(InFlight::test),
+                    )
+                })
+                .filter(|(frame, clocks)| frame.is_some() || clocks.is_some())
+                .collect();
+        }
+        let receivers: Vec<Rank> = self.held.keys().copied().collect();
+        for receiver in receivers {
+            self.start_held(world, receiver);
+        }
+    }
+
+    // Messages in frames that have not been handed to MPI yet
+    pub fn held(&self) -> impl Iterator<Item = &MessagePayload> {
+        self.held
+            .values()
+            .flat_map(|frames| frames.iter().flat_map(|(frame, _)| frame.iter()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn message(value: i32, receiver: Rank) -> MessagePayload {
+        MessagePayload::new(1, value, 0, 0, receiver, VectorClock::default())
+    }
+
+    fn values(frame: &[MessagePayload]) -> Vec<i32> {
+        frame.iter().map(|m| m.value).collect()
+    }
+
+    #[test]
+    fn policies_parse() {
+        assert_eq!("immediate".parse(), Ok(FlushPolicy::Immediate));
+        assert_eq!("step".parse(), Ok(FlushPolicy::Step));
+        assert_eq!(
+            "delay:25".parse(),
+            Ok(FlushPolicy::Delay(Duration::from_millis(25)))
+        );
+        assert!("delay:soon".parse::<FlushPolicy>().is_err());
+        assert!("delay".parse::<FlushPolicy>().is_err());
+        assert!("batch".parse::<FlushPolicy>().is_err());
+    }
+
+    #[test]
+    fn immediate_sends_every_message_on_its_own() {
+        let mut outbox = Outbox::new(FlushPolicy::Immediate, 64);
+        let (receiver, frame) = outbox.push(message(1, 2)).unwrap();
+        assert_eq!((receiver, values(&frame)), (2, vec![1]));
+        assert_eq!(outbox.pending().count(), 0);
+    }
+
+    #[test]
+    fn frames_keep_message_order_per_destination() {
+        let mut outbox = Outbox::new(FlushPolicy::Step, 64);
+        for (value, receiver) in [(1, 2), (2, 1), (3, 2), (4, 1), (5, 2)] {
+            assert!(outbox.push(message(value, receiver)).is_none());
+        }
+        assert_eq!(outbox.pending().count(), 5);
+        let frames: Vec<(Rank, Vec<i32>)> = outbox
+            .due()
+            .into_iter()
+            .map(|(receiver, frame)| (receiver, values(&frame)))
+            .collect();
+        assert_eq!(frames, vec![(1, vec![2, 4]), (2, vec![1, 3, 5])]);
+        assert!(outbox.due().is_empty());
+    }
+
+    #[test]
+    fn a_full_frame_leaves_at_once() {
+        let mut outbox = Outbox::new(FlushPolicy::Delay(Duration::from_secs(60)), 3);
+        assert!(outbox.push(message(1, 0)).is_none());
+        assert!(outbox.push(message(2, 1)).is_none());
+        assert!(outbox.push(message(3, 0)).is_none());
+        let (receiver, frame) = outbox.push(message(4, 0)).unwrap();
+        assert_eq!((receiver, values(&frame)), (0, vec![1, 3, 4]));
+        // The next message to that destination opens a new frame
+        assert!(outbox.push(message(5, 0)).is_none());
+        assert_eq!(outbox.pending().count(), 2);
+    }
+
+    #[test]
+    fn delay_holds_frames_until_they_are_old_enough() {
+        let delay = Duration::from_millis(50);
+        let mut outbox = Outbox::new(FlushPolicy::Delay(delay), 64);
+        outbox.push(message(1, 0));
+        assert!(outbox.due().is_empty());
+        thread::sleep(delay);
+        // A frame's age counts from its first message
+        outbox.push(message(2, 1));
+        outbox.push(message(3, 0));
+        let frames = outbox.due();
+        assert_eq!(frames.len(), 1);
+        assert_eq!((frames[0].0, values(&frames[0].1)), (0, vec![1, 3]));
+        let drained = outbox.drain();
+        assert_eq!(drained.len(), 1);
+        assert_eq!((drained[0].0, values(&drained[0].1)), (1, vec![2]));
+    }
+}
@@ -0,0 +1,223 @@
+use mpi::Rank;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::message_payload::{MessagePayload, VectorClock};
+use crate::process_data::{Completion, OpKind};
+
+// Message complexity per operation. Every EnqReq, EnqAck, DeqReq and DeqAck names the
+// operation it belongs to by its invoker and timestamp, so each process can tally what it
+// sent and received on behalf of each Enqueue and Dequeue. Invocations are handed to the
+// process by its client and are not counted.
+//
+// An operation is completed here once this process has nothing more to do for it: at the
+// invoker when the operation returns, elsewhere when an Enqueue's EnqReq arrives or a
+// Dequeue executes. Completed operations are folded into running totals per kind at the
+// end of the step, so acknowledgements sent in the same step still count towards them,
+// and only operations under way are kept. Only an EnqReq or DeqReq starts a tally; an
+// acknowledgement for an operation with none, e.g. one arriving after a quorum already
+// let the operation return, goes straight into the totals.
+// Every operation has one invoker, so summing `sent` over all ranks and dividing by the
+// summed `invoked` gives the messages one operation costs the whole cluster.
+type OpKey = (OpKind, Rank, VectorClock);
+
+#[derive(Clone, Debug, Default)]
+struct OpTally {
+    sent: BTreeMap<&'static str, u64>,
+    received: BTreeMap<&'static str, u64>,
+}
+
+impl OpTally {
+    fn sent_total(&self) -> u64 {
+        self.sent.values().sum()
+    }
+}
+
+// Totals over the completed operations of one kind
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ComplexityReport {
+    // Operations completed here, and how many of those were invoked here
+    pub operations: u64,
+    pub invoked: u64,
+    pub sent: BTreeMap<&'static str, u64>,
+    pub received: BTreeMap<&'static str, u64>,
+    // Fewest and most messages this process sent for a single operation
+    pub min_sent: u64,
+    pub max_sent: u64,
+}
+
+impl ComplexityReport {
+    fn fold(&mut self, tally: OpTally, invoked: bool) {
+        let sent = tally.sent_total();
+        self.min_sent = if self.operations == 0 {
+            sent
+        } else {
+            self.min_sent.min(sent)
+        };
+        self.max_sent = self.max_sent.max(sent);
+        self.operations += 1;
+        if invoked {
+            self.invoked += 1;
+        }
+        for (kind, count) in tally.sent {
+            *self.sent.entry(kind).or_default() += count;
+        }
+        for (kind, count) in tally.received {
+            *self.received.entry(kind).or_default() += count;
+        }
+    }
+}
+
+pub struct MessageComplexity {
+    rank: Rank,
+    // Operations under way here
+    open: BTreeMap<OpKey, OpTally>,
+    // Operations completed during the current step
+    completed: Vec<OpKey>,
+    reports: BTreeMap<&'static str, ComplexityReport>,
+}
+
+fn operation(message: &MessagePayload) -> Option<OpKey> {
+    let kind = match message.message {
+        1 | 2 => OpKind::Enqueue,
+        4 | 5 => OpKind::Dequeue,
+        _ => return None,
+    };
+    Some((kind, message.invoker, message.time_stamp))
+}
+
+impl MessageComplexity {
+    pub fn new(rank: Rank) -> Self {
+        MessageComplexity {
+            rank,
+            open: BTreeMap::new(),
+            completed: Vec::new(),
+            reports: BTreeMap::new(),
+        }
+    }
+
+    // The sent or received counts a message of operation `key` belongs to
+    fn counts(
+        &mut self,
+        key: OpKey,
+        message: &MessagePayload,
+        received: bool,
+    ) -> &mut BTreeMap<&'static str, u64> {
+        let starts = message.message == 1 || message.message == 4;
+        if starts || self.open.contains_key(&key) {
+            let tally = self.open.entry(key).or_default();
+            return if received {
+                &mut tally.received
+            } else {
+                &mut tally.sent
+            };
+        }
+        let report = self.reports.entry(key.0.name()).or_default();
+        if received {
+            &mut report.received
+        } else {
+            &mut report.sent
+        }
+    }
+
+    pub fn on_sent(&mut self, message: &MessagePayload) {
+        if let Some(key) = operation(message) {
+            *self
+                .counts(key, message, false)
+                .entry(message.kind())
+                .or_default() += 1;
+        }
+    }
+
+    pub fn on_received(&mut self, message: &MessagePayload) {
+        if let Some(key) = operation(message) {
+            *self
+                .counts(key, message, true)
+                .entry(message.kind())
+                .or_default() += 1;
+            if message.message == 1 && message.invoker != self.rank {
+                self.completed.push(key);
+            }
+        }
+    }
+
+    // Operations invoked here that returned; rejected ones sent nothing and are skipped
+    pub fn on_completion(&mut self, completion: &Completion) {
+        self.completed
+            .push((completion.kind, self.rank, completion.ts));
+    }
+
+    // Dequeues executed here, as (timestamp, invoker, removed value)
+    pub fn on_executed(&mut self, executed: &[(VectorClock, Rank, i32)]) {
+        for &(ts, invoker, _) in executed {
+            self.completed.push((OpKind::Dequeue, invoker, ts));
+        }
+    }
+
+    // Folds the operations completed during the step into the totals, once the step's
+    // messages have been sent
+    pub fn end_step(&mut self) {
+        for key in std::mem::take(&mut self.completed) {
+            if let Some(tally) = self.open.remove(&key) {
+                self.reports
+                    .entry(key.0.name())
+                    .or_default()
+                    .fold(tally, key.1 == self.rank);
+            }
+        }
+    }
+
+    pub fn report(&self) -> &BTreeMap<&'static str, ComplexityReport> {
+        &self.reports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process_data::OpOutcome;
+    use std::time::Duration;
+
+    #[test]
+    fn completed_operations_are_folded_and_late_acks_still_count() {
+        let ts = VectorClock::try_from(vec![1, 0, 0]).unwrap();
+        let mut complexity = MessageComplexity::new(0);
+        for receiver in 0..3 {
+            complexity.on_sent(&MessagePayload::new(1, 7, 0, 0, receiver, ts));
+        }
+        complexity.on_received(&MessagePayload::new(1, 7, 0, 0, 0, ts));
+        complexity.on_received(&MessagePayload::new(2, 7, 0, 1, 0, ts));
+        complexity.on_completion(&Completion {
+            op_id: 0,
+            kind: OpKind::Enqueue,
+            ts,
+            outcome: OpOutcome::Enqueued,
+            latency: Duration::ZERO,
+        });
+        complexity.end_step();
+        assert!(complexity.open.is_empty());
+
+        // The third acknowledgement arrives after a quorum let the Enqueue return
+        complexity.on_received(&MessagePayload::new(2, 7, 0, 2, 0, ts));
+        complexity.end_step();
+        assert!(complexity.open.is_empty());
+        let report = &complexity.report()["enqueue"];
+        assert_eq!((report.operations, report.invoked), (1, 1));
+        assert_eq!((report.min_sent, report.max_sent), (3, 3));
+        assert_eq!(report.sent["EnqReq"], 3);
+        assert_eq!(report.received["EnqReq"], 1);
+        assert_eq!(report.received["EnqAck"], 2);
+    }
+
+    #[test]
+    fn acks_sent_in_the_completing_step_count_towards_the_operation() {
+        let ts = VectorClock::try_from(vec![0, 1]).unwrap();
+        let mut complexity = MessageComplexity::new(0);
+        complexity.on_received(&MessagePayload::new(1, 7, 1, 1, 0, ts));
+        complexity.on_sent(&MessagePayload::new(2, 7, 1, 0, 1, ts));
+        complexity.end_step();
+        let report = &complexity.report()["enqueue"];
+        assert_eq!((report.operations, report.invoked), (1, 0));
+        assert_eq!(report.max_sent, 1);
+    }
+}
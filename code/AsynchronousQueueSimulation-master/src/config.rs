@@ -0,0 +1,182 @@
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::batch::FlushPolicy;
+use crate::logging::LogFormat;
+use crate::message_payload::MAX_BUFFER_SIZE;
+use crate::process_data::{AckPolicy, ClockRule};
+use crate::sequence::DiagramOptions;
+use crate::timestamp_order::TimestampOrder;
+
+// Startup options shared by every rank, read from the command line, e.g.
+// `mpirun -n 4 async_queue --clock-rule paper`
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub clock_rule: ClockRule,
+    pub ts_order: TimestampOrder,
+    pub ack_policy: AckPolicy,
+    // Ranks below this start as members, the rest as spares that may join (default: all)
+    pub initial_members: Option<i32>,
+    pub heartbeat_interval: Duration,
+    pub suspect_timeout: Duration,
+    // Each rank checkpoints to `<snapshot_dir>/rank<r>.json` every `snapshot_every` messages
+    pub snapshot_dir: Option<PathBuf>,
+    pub snapshot_every: usize,
+    // Log every applied message to `<wal_dir>/rank<r>.wal` before acknowledging it
+    pub wal_dir: Option<PathBuf>,
+    // Start from the snapshot in `snapshot_dir` and the log in `wal_dir` instead of an
+    // empty replica
+    pub restore: bool,
+    // Where the initiator of a global snapshot writes `global_snapshot_<id>.json`
+    pub global_snapshot_dir: PathBuf,
+    // Rank that checks every replica removed the same value for each Dequeue
+    pub auditor_rank: Option<i32>,
+    // Members exchange Dequeue digests every this many executed Dequeues (0 disables)
+    pub digest_every: u64,
+    // How long a JSON client request waits for its operation to respond
+    pub client_timeout: Duration,
+    // Each rank serves the HTTP front-end on `http_base_port + rank` when set
+    pub http_base_port: Option<u16>,
+    // Run this many processes in an interactive shell instead of joining an MPI job
+    pub repl: Option<i32>,
+    // Each rank writes a ShiViz event log to `<shiviz_dir>/rank<r>.shiviz`, with the rank
+    // zero-padded to two digits. Every rank must be given it, or none.
+    pub shiviz_dir: Option<PathBuf>,
+    // Render recorded traces as a sequence diagram instead of joining an MPI job
+    pub diagram: Option<DiagramOptions>,
+    // Each rank serves Prometheus metrics on `metrics_base_port + rank` when set
+    pub metrics_base_port: Option<u16>,
+    // `RUST_LOG` style directives, read from the environment by default
+    pub log_filter: String,
+    pub log_format: LogFormat,
+    // When outbound messages are coalesced into one frame per destination, and the most
+    // messages a frame may carry
+    pub batch: FlushPolicy,
+    pub batch_max: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            clock_rule: ClockRule::default(),
+            ts_order: TimestampOrder::default(),
+            ack_policy: AckPolicy::default(),
+            initial_members: None,
+            heartbeat_interval: Duration::from_millis(500),
+            suspect_timeout: Duration::from_millis(3000),
+            snapshot_dir: None,
+            snapshot_every: 100,
+            wal_dir: None,
+            restore: false,
+            global_snapshot_dir: PathBuf::from("."),
+            auditor_rank: None,
+            digest_every: 16,
+            client_timeout: Duration::from_millis(30000),
+            http_base_port: None,
+            repl: None,
+            shiviz_dir: None,
+            diagram: None,
+            metrics_base_port: None,
+            log_filter: env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string()),
+            log_format: LogFormat::default(),
+            batch: FlushPolicy::default(),
+            batch_max: 64,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args() -> Self {
+        Self::parse(env::args().skip(1))
+    }
+
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Self {
+        let mut config = Config::default();
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .unwrap_or_else(|| panic!("Missing value for argument {}", arg))
+            };
+            match arg.as_str() {
+                "--clock-rule" => config.clock_rule = value().parse().unwrap(),
+                "--ts-order" => config.ts_order = value().parse().unwrap(),
+                "--ack-policy" => config.ack_policy = value().parse().unwrap(),
+                "--initial-members" => config.initial_members = Some(value().parse().unwrap()),
+                "--heartbeat-ms" => {
+                    config.heartbeat_interval = Duration::from_millis(value().parse().unwrap())
+                }
+                "--suspect-timeout-ms" => {
+                    config.suspect_timeout = Duration::from_millis(value().parse().unwrap())
+                }
+                "--snapshot-dir" => config.snapshot_dir = Some(PathBuf::from(value())),
+                "--snapshot-every" => config.snapshot_every = value().parse().unwrap(),
+                "--wal-dir" => config.wal_dir = Some(PathBuf::from(value())),
+                "--restore" => config.restore = true,
+                "--global-snapshot-dir" => config.global_snapshot_dir = PathBuf::from(value()),
+                "--auditor-rank" => config.auditor_rank = Some(value().parse().unwrap()),
+                "--digest-every" => config.digest_every = value().parse().unwrap(),
+                "--client-timeout-ms" => {
+                    config.client_timeout = Duration::from_millis(value().parse().unwrap())
+                }
+                "--http-base-port" => config.http_base_port = Some(value().parse().unwrap()),
+                "--repl" => config.repl = Some(value().parse().unwrap()),
+                "--shiviz-dir" => config.shiviz_dir = Some(PathBuf::from(value())),
+                "--diagram" => {
+                    config.diagram.get_or_insert_with(Default::default).format =
+                        value().parse().unwrap()
+                }
+                "--trace" => config
+                    .diagram
+                    .get_or_insert_with(Default::default)
+                    .traces
+                    .push(PathBuf::from(value())),
+                "--positions" => {
+                    let positions = value();
+                    let (from, to) = positions.split_once(':').unwrap_or_else(|| {
+                        panic!("--positions expects FROM:TO, got {}", positions)
+                    });
+                    config
+                        .diagram
+                        .get_or_insert_with(Default::default)
+                        .positions = Some((from.parse().unwrap(), to.parse().unwrap()))
+                }
+                "--operation" => {
+                    config
+                        .diagram
+                        .get_or_insert_with(Default::default)
+                        .operation = Some(value().parse().unwrap())
+                }
+                "--metrics-base-port" => config.metrics_base_port = Some(value().parse().unwrap()),
+                "--log-filter" => config.log_filter = value(),
+                "--log-format" => config.log_format = value().parse().unwrap(),
+                "--batch" => config.batch = value().parse().unwrap(),
+                "--batch-max" => config.batch_max = value().parse().unwrap(),
+                _ => panic!("Unknown argument {}", arg),
+            }
+        }
+        if config.restore && config.snapshot_dir.is_none() && config.wal_dir.is_none() {
+            panic!("--restore requires --snapshot-dir or --wal-dir");
+        }
+        if config
+            .diagram
+            .as_ref()
+            .is_some_and(|diagram| diagram.traces.is_empty())
+        {
+            panic!("--diagram requires at least one --trace");
+        }
+        if config.batch_max == 0 {
+            panic!("--batch-max must be at least 1");
+        }
+        if let Some(size) = config.repl {
+            if !(1..=MAX_BUFFER_SIZE as i32).contains(&size) {
+                panic!(
+                    "--repl must be between 1 and {} nodes, got {}",
+                    MAX_BUFFER_SIZE, size
+                );
+            }
+        }
+        config
+    }
+}
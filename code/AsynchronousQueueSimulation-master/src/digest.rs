@@ -0,0 +1,266 @@
+use mpi::Rank;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::error;
+
+use crate::message_payload::{MessagePayload, VectorClock};
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// FNV-1a, chosen over std's hasher so digests are stable across builds and machines
+fn fnv1a(hash: u64, words: &[i32]) -> u64 {
+    let mut hash = hash;
+    for word in words {
+        for byte in word.to_le_bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+    hash
+}
+
+fn element_hash(value: i32, invoker: Rank, ts: &VectorClock) -> u64 {
+    let hash = fnv1a(FNV_OFFSET, &[value, invoker]);
+    fnv1a(hash, &ts.clock[..ts.size])
+}
+
+// Incremental digests of a replica. Replicas apply EnqReqs in arrival order, so the
+// enqueue and queue digests are order-independent sums of element hashes. Dequeues execute
+// in timestamp order everywhere, so their digest is a hash chain: two replicas that executed
+// the same number of Dequeues agree on `deq_chain` exactly when they removed the same values.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ReplicaDigest {
+    pub enqueues: u64,
+    pub enqueue_count: u64,
+    pub deq_chain: u64,
+    pub dequeue_count: u64,
+    pub queue: u64,
+    // (dequeue_count, deq_chain) every `checkpoint_every` Dequeues, drained by main
+    #[serde(skip)]
+    pub checkpoints: Vec<(u64, u64)>,
+    #[serde(skip)]
+    pub checkpoint_every: u64,
+}
+
+impl ReplicaDigest {
+    pub fn new(checkpoint_every: u64) -> Self {
+        ReplicaDigest {
+            deq_chain: FNV_OFFSET,
+            checkpoint_every,
+            ..Default::default()
+        }
+    }
+
+    pub fn on_enqueue(&mut self, value: i32, invoker: Rank, ts: &VectorClock) {
+        self.enqueues = self.enqueues.wrapping_add(element_hash(value, invoker, ts));
+        self.enqueue_count += 1;
+    }
+
+    pub fn on_insert(&mut self, value: i32, invoker: Rank, ts: &VectorClock) {
+        self.queue = self.queue.wrapping_add(element_hash(value, invoker, ts));
+    }
+
+    pub fn on_remove(&mut self, value: i32, invoker: Rank, ts: &VectorClock) {
+        self.queue = self.queue.wrapping_sub(element_hash(value, invoker, ts));
+    }
+
+    pub fn on_dequeue(&mut self, ts: &VectorClock, removed: i32) {
+        self.deq_chain = fnv1a(self.deq_chain, &ts.clock[..ts.size]);
+        self.deq_chain = fnv1a(self.deq_chain, &[removed]);
+        self.dequeue_count += 1;
+        if self.checkpoint_every > 0 && self.dequeue_count.is_multiple_of(self.checkpoint_every) {
+            self.checkpoints.push((self.dequeue_count, self.deq_chain));
+        }
+    }
+}
+
+fn pack(words: [u64; 2]) -> VectorClock {
+    let mut ts = VectorClock::new(4);
+    for (i, word) in words.iter().enumerate() {
+        ts.clock[2 * i] = (word >> 32) as i32;
+        ts.clock[2 * i + 1] = *word as i32;
+    }
+    ts
+}
+
+fn unpack(ts: &VectorClock) -> [u64; 2] {
+    let word =
+        |i: usize| ((ts.clock[2 * i] as u32 as u64) << 32) | ts.clock[2 * i + 1] as u32 as u64;
+    [word(0), word(1)]
+}
+
+// Compares Dequeue hash chains piggybacked (21) by the other members at the same logical
+// point, i.e. after the same number of executed Dequeues. A process that joined through a
+// reconfiguration starts its chain from scratch, so its checkpoints only line up with the
+// others' if it joined before any Dequeue executed.
+pub struct DigestChecker {
+    rank: Rank,
+    local: BTreeMap<u64, u64>,
+    // Remote checkpoints this process has not reached yet
+    remote: Vec<(Rank, u64, u64)>,
+    pub divergences: u64,
+}
+
+impl DigestChecker {
+    // Own checkpoints older than this many are forgotten
+    const HISTORY: usize = 64;
+
+    pub fn new(rank: Rank) -> Self {
+        DigestChecker {
+            rank,
+            local: BTreeMap::new(),
+            remote: Vec::new(),
+            divergences: 0,
+        }
+    }
+
+    // Records an own checkpoint and returns the messages announcing it to `members`
+    pub fn on_local(
+        &mut self,
+        dequeue_count: u64,
+        deq_chain: u64,
+        members: &[Rank],
+    ) -> Vec<MessagePayload> {
+        self.local.insert(dequeue_count, deq_chain);
+        while self.local.len() > Self::HISTORY {
+            self.local.pop_first();
+        }
+        let pending = std::mem::take(&mut self.remote);
+        for (sender, count, chain) in pending {
+            self.compare(sender, count, chain);
+        }
+        let ts = pack([dequeue_count, deq_chain]);
+        members
+            .iter()
+            .filter(|&&r| r != self.rank)
+            .map(|&recv_rank| MessagePayload::new(21, 0, self.rank, self.rank, recv_rank, ts))
+            .collect()
+    }
+
+    pub fn on_remote(&mut self, message: &MessagePayload) {
+        let [count, chain] = unpack(&message.time_stamp);
+        self.compare(message.sender, count, chain);
+    }
+
+    fn compare(&mut self, sender: Rank, count: u64, chain: u64) {
+        match self.local.get(&count) {
+            Some(&local_chain) if local_chain != chain => {
+                self.divergences += 1;
+                error!(
+                    dequeues = count,
+                    "DIGEST MISMATCH: local chain {:016x}, process {} has {:016x}",
+                    local_chain,
+                    sender,
+                    chain
+                );
+            }
+            Some(_) => {}
+            None => {
+                // Not reached yet; checkpoints older than the history are dropped
+                if self
+                    .local
+                    .last_key_value()
+                    .is_none_or(|(&last, _)| count > last)
+                {
+                    self.remote.push((sender, count, chain));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkpoint(sender: Rank, receiver: Rank, count: u64, chain: u64) -> MessagePayload {
+        MessagePayload::new(21, 0, sender, sender, receiver, pack([count, chain]))
+    }
+
+    #[test]
+    fn pack_round_trips_full_words() {
+        for words in [
+            [0, 0],
+            [1, FNV_OFFSET],
+            [u64::MAX, 0x8000_0000],
+            [0xffff_ffff, 0x8000_0000_8000_0000],
+        ] {
+            assert_eq!(unpack(&pack(words)), words);
+        }
+        // Survives the trip through a message's timestamp on the wire
+        let json = serde_json::to_string(&checkpoint(1, 0, u64::MAX, FNV_OFFSET)).unwrap();
+        let message: MessagePayload = serde_json::from_str(&json).unwrap();
+        assert_eq!(unpack(&message.time_stamp), [u64::MAX, FNV_OFFSET]);
+    }
+
+    #[test]
+    fn chains_agree_exactly_when_the_same_values_were_removed() {
+        let ts = VectorClock::try_from(vec![1, 0]).unwrap();
+        let mut a = ReplicaDigest::new(0);
+        let mut b = ReplicaDigest::new(0);
+        a.on_dequeue(&ts, 5);
+        b.on_dequeue(&ts, 5);
+        assert_eq!(a.deq_chain, b.deq_chain);
+        a.on_dequeue(&ts, 6);
+        b.on_dequeue(&ts, 7);
+        assert_ne!(a.deq_chain, b.deq_chain);
+    }
+
+    #[test]
+    fn checkpoints_are_announced_to_the_other_members() {
+        let mut checker = DigestChecker::new(1);
+        let sent = checker.on_local(2, 42, &[0, 1, 3]);
+        let receivers: Vec<Rank> = sent.iter().map(|m| m.receiver).collect();
+        assert_eq!(receivers, vec![0, 3]);
+        assert!(sent
+            .iter()
+            .all(|m| m.message == 21 && unpack(&m.time_stamp) == [2, 42]));
+    }
+
+    #[test]
+    fn matching_chains_are_not_divergences() {
+        let mut checker = DigestChecker::new(0);
+        checker.on_local(2, 42, &[0, 1]);
+        checker.on_remote(&checkpoint(1, 0, 2, 42));
+        assert_eq!(checker.divergences, 0);
+        // A checkpoint this process has not reached yet waits for it
+        checker.on_remote(&checkpoint(1, 0, 4, 77));
+        checker.on_local(4, 77, &[0, 1]);
+        assert_eq!(checker.divergences, 0);
+        assert!(checker.remote.is_empty());
+    }
+
+    #[test]
+    fn diverging_chains_are_counted_once_each() {
+        let mut checker = DigestChecker::new(0);
+        checker.on_local(2, 42, &[0, 1, 2]);
+        checker.on_remote(&checkpoint(1, 0, 2, 43));
+        assert_eq!(checker.divergences, 1);
+        checker.on_remote(&checkpoint(2, 0, 4, 10));
+        checker.on_local(4, 11, &[0, 1, 2]);
+        assert_eq!(checker.divergences, 2);
+        // Compared once, not again at the next checkpoint
+        checker.on_local(6, 12, &[0, 1, 2]);
+        assert_eq!(checker.divergences, 2);
+    }
+
+    #[test]
+    fn checkpoints_of_a_member_that_left_are_still_compared() {
+        let mut checker = DigestChecker::new(0);
+        checker.on_local(2, 42, &[0, 1, 2]);
+        // Rank 2 announced two checkpoints, then left before this process reached the second
+        checker.on_remote(&checkpoint(2, 0, 2, 42));
+        checker.on_remote(&checkpoint(2, 0, 4, 99));
+        let sent = checker.on_local(4, 99, &[0, 1]);
+        assert!(sent.iter().all(|m| m.receiver == 1));
+        assert_eq!(checker.divergences, 0);
+        // A checkpoint older than anything still remembered is dropped, not kept forever
+        for count in 1..=DigestChecker::HISTORY as u64 {
+            checker.on_local(4 + 2 * count, count, &[0, 1]);
+        }
+        checker.on_remote(&checkpoint(2, 0, 2, 41));
+        assert_eq!(checker.divergences, 0);
+        assert!(checker.remote.is_empty());
+    }
+}
@@ -0,0 +1,160 @@
+use mpi::Rank;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::message_payload::{MessagePayload, VectorClock};
+
+// Liveness information shared with the client server threads
+#[derive(Clone, Debug, Default)]
+pub struct HealthStatus {
+    pub suspected: Vec<Rank>,
+    pub stalled: Vec<String>,
+}
+
+pub type SharedHealth = Arc<Mutex<HealthStatus>>;
+
+// Eventually-perfect style failure detector: every process broadcasts a heartbeat each
+// `heartbeat_interval`, and a peer is suspected once nothing has been heard from it for
+// `suspect_timeout`. Any received message counts as a sign of life. A suspected peer that
+// is heard from again is no longer suspected. Callers pass in the current time, so tests can
+// drive the detector with a fake clock.
+pub struct FailureDetector {
+    rank: Rank,
+    world_size: i32,
+    heartbeat_interval: Duration,
+    suspect_timeout: Duration,
+    last_heartbeat: Instant,
+    last_heard: Vec<Instant>,
+    suspected: Vec<bool>,
+}
+
+impl FailureDetector {
+    pub fn new(rank: Rank, size: i32, config: &Config, now: Instant) -> Self {
+        FailureDetector {
+            rank,
+            world_size: size,
+            heartbeat_interval: config.heartbeat_interval,
+            suspect_timeout: config.suspect_timeout,
+            last_heartbeat: now,
+            last_heard: vec![now; size as usize],
+            suspected: vec![false; size as usize],
+        }
+    }
+
+    pub fn heard_from(&mut self, sender: Rank, now: Instant) {
+        self.last_heard[sender as usize] = now;
+    }
+
+    // Heartbeats to broadcast if an interval has passed since the last round
+    pub fn heartbeats_due(&mut self, ts: VectorClock, now: Instant) -> Vec<MessagePayload> {
+        let mut heartbeats = Vec::new();
+        if now.duration_since(self.last_heartbeat) < self.heartbeat_interval {
+            return heartbeats;
+        }
+        self.last_heartbeat = now;
+        for recv_rank in 0..self.world_size {
+            if recv_rank != self.rank {
+                heartbeats.push(MessagePayload::new(
+                    6, 0, self.rank, self.rank, recv_rank, ts,
+                ));
+            }
+        }
+        heartbeats
+    }
+
+    // Re-evaluates every peer, returning true if the set of suspected ranks changed
+    pub fn update_suspicions(&mut self, now: Instant) -> bool {
+        let mut changed = false;
+        for peer in 0..self.world_size as usize {
+            if peer == self.rank as usize {
+                continue;
+            }
+            let suspect = now.duration_since(self.last_heard[peer]) > self.suspect_timeout;
+            if suspect != self.suspected[peer] {
+                if suspect {
+                    warn!(peer, "suspect peer has failed");
+                } else {
+                    info!(peer, "heard from suspected peer again");
+                }
+                self.suspected[peer] = suspect;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    pub fn suspected_ranks(&self) -> Vec<Rank> {
+        (0..self.world_size)
+            .filter(|&peer| self.suspected[peer as usize])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector(start: Instant) -> FailureDetector {
+        let config = Config {
+            heartbeat_interval: Duration::from_millis(500),
+            suspect_timeout: Duration::from_millis(3000),
+            ..Config::default()
+        };
+        FailureDetector::new(1, 3, &config, start)
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn heartbeats_go_to_every_peer_once_per_interval() {
+        let start = Instant::now();
+        let mut fd = detector(start);
+        let ts = VectorClock::new(3);
+        assert!(fd.heartbeats_due(ts, start + ms(499)).is_empty());
+
+        let heartbeats = fd.heartbeats_due(ts, start + ms(500));
+        let receivers: Vec<Rank> = heartbeats.iter().map(|m| m.receiver).collect();
+        assert_eq!(receivers, vec![0, 2]);
+        assert!(heartbeats.iter().all(|m| m.message == 6 && m.sender == 1));
+        // The next round is an interval after the previous one, not after the start
+        assert!(fd.heartbeats_due(ts, start + ms(900)).is_empty());
+        assert_eq!(fd.heartbeats_due(ts, start + ms(1000)).len(), 2);
+        // A late round does not cause a burst of catching up
+        assert_eq!(fd.heartbeats_due(ts, start + ms(5000)).len(), 2);
+        assert!(fd.heartbeats_due(ts, start + ms(5100)).is_empty());
+    }
+
+    #[test]
+    fn silent_peers_are_suspected_after_the_timeout() {
+        let start = Instant::now();
+        let mut fd = detector(start);
+        assert!(!fd.update_suspicions(start + ms(3000)));
+        assert!(fd.suspected_ranks().is_empty());
+
+        fd.heard_from(2, start + ms(2000));
+        assert!(fd.update_suspicions(start + ms(3001)));
+        assert_eq!(fd.suspected_ranks(), vec![0]);
+        // Unchanged until rank 2 has been silent for the timeout as well
+        assert!(!fd.update_suspicions(start + ms(5000)));
+        assert!(fd.update_suspicions(start + ms(5001)));
+        assert_eq!(fd.suspected_ranks(), vec![0, 2]);
+    }
+
+    #[test]
+    fn suspected_peers_recover_when_heard_from() {
+        let start = Instant::now();
+        let mut fd = detector(start);
+        assert!(fd.update_suspicions(start + ms(4000)));
+        assert_eq!(fd.suspected_ranks(), vec![0, 2]);
+
+        fd.heard_from(0, start + ms(4100));
+        assert!(fd.update_suspicions(start + ms(4200)));
+        assert_eq!(fd.suspected_ranks(), vec![2]);
+        // Its own rank is never suspected
+        assert!(!fd.suspected_ranks().contains(&1));
+    }
+}
@@ -0,0 +1,338 @@
+use mpi::Rank;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{error, info, warn};
+
+use crate::message_payload::{MessagePayload, VectorClock};
+use crate::process_data::ProcessData;
+use crate::snapshot::Snapshot;
+
+// Bytes of a serialized record carried by one chunk message, packed into the clock entries
+const CHUNK_BYTES: usize = 4 * 32;
+
+// What one process recorded for a global snapshot: its replica, the invocations it had not
+// sent yet, and for every incoming channel (indexed by sender) the messages in flight
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProcessRecord {
+    pub rank: Rank,
+    pub state: Snapshot,
+    pub outbound: Vec<MessagePayload>,
+    pub channels: Vec<Vec<MessagePayload>>,
+}
+
+// Consistent cut of every process, written by the initiator as a single JSON file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GlobalSnapshot {
+    pub id: i32,
+    pub initiator: Rank,
+    pub processes: Vec<ProcessRecord>,
+}
+
+impl GlobalSnapshot {
+    pub fn path(dir: &Path, id: i32) -> PathBuf {
+        dir.join(format!("global_snapshot_{}.json", id))
+    }
+}
+
+// Up to CHUNK_BYTES bytes as little-endian clock entries, the last one zero-padded
+fn pack_chunk(bytes: &[u8]) -> VectorClock {
+    let mut ts = VectorClock::new(bytes.len().div_ceil(4) as i32);
+    for (i, word) in bytes.chunks(4).enumerate() {
+        let mut padded = [0u8; 4];
+        padded[..word.len()].copy_from_slice(word);
+        ts.clock[i] = i32::from_le_bytes(padded);
+    }
+    ts
+}
+
+// The bytes of a chunk message (18), whose value is the unpadded length
+fn unpack_chunk(chunk: &MessagePayload) -> Vec<u8> {
+    let words = &chunk.time_stamp.clock[..chunk.time_stamp.size];
+    let mut bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    bytes.truncate(chunk.value as usize);
+    bytes
+}
+
+struct Recording {
+    id: i32,
+    initiator: Rank,
+    record: ProcessRecord,
+    markers_received: Vec<bool>,
+}
+
+struct Collection {
+    id: i32,
+    buffers: Vec<Vec<u8>>,
+    records: Vec<Option<ProcessRecord>>,
+}
+
+// Chandy-Lamport snapshot over the MPI channels, which are FIFO between every pair of
+// processes (including a process and itself). Markers (17) are sent on every channel as
+// soon as a process records its state; a channel is recorded from then until its marker
+// arrives. Each process then ships its record to the initiator as chunks (18, 19).
+pub struct GlobalSnapshotter {
+    rank: Rank,
+    world_size: i32,
+    dir: PathBuf,
+    recording: Option<Recording>,
+    collection: Option<Collection>,
+}
+
+impl GlobalSnapshotter {
+    pub fn new(rank: Rank, size: i32, dir: PathBuf) -> Self {
+        GlobalSnapshotter {
+            rank,
+            world_size: size,
+            dir,
+            recording: None,
+            collection: None,
+        }
+    }
+
+    // Snapshot invoke (16) at the initiator; returns the markers, which must be sent before
+    // any other message
+    pub fn start(
+        &mut self,
+        id: i32,
+        process_data: &ProcessData,
+        outbound: &[MessagePayload],
+    ) -> Vec<MessagePayload> {
+        if self.recording.is_some() || self.collection.is_some() {
+            warn!(id, "already taking a global snapshot, ignoring request");
+            return Vec::new();
+        }
+        self.collection = Some(Collection {
+            id,
+            buffers: vec![Vec::new(); self.world_size as usize],
+            records: vec![None; self.world_size as usize],
+        });
+        self.record_local_state(id, self.rank, process_data, outbound)
+    }
+
+    fn record_local_state(
+        &mut self,
+        id: i32,
+        initiator: Rank,
+        process_data: &ProcessData,
+        outbound: &[MessagePayload],
+    ) -> Vec<MessagePayload> {
+        self.recording = Some(Recording {
+            id,
+            initiator,
+            record: ProcessRecord {
+                rank: self.rank,
+                state: process_data.snapshot(),
+                outbound: outbound.to_vec(),
+                channels: vec![Vec::new(); self.world_size as usize],
+            },
+            markers_received: vec![false; self.world_size as usize],
+        });
+        (0..self.world_size)
+            .map(|recv_rank| {
+                MessagePayload::new(
+                    17,
+                    id,
+                    initiator,
+                    self.rank,
+                    recv_rank,
+                    process_data.timestamp,
+                )
+            })
+            .collect()
+    }
+
+    // Returns the markers to send immediately and the chunks of a finished local record
+    pub fn on_marker(
+        &mut self,
+        marker: &MessagePayload,
+        process_data: &ProcessData,
+        outbound: &[MessagePayload],
+    ) -> (Vec<MessagePayload>, Vec<MessagePayload>) {
+        let mut markers = Vec::new();
+        if self.recording.is_none() {
+            markers = self.record_local_state(marker.value, marker.invoker, process_data, outbound);
+        }
+        let recording = self.recording.as_mut().unwrap();
+        if recording.id != marker.value {
+            warn!(
+                marker = marker.value,
+                recording = recording.id,
+                "ignoring marker for another snapshot"
+            );
+            return (markers, Vec::new());
+        }
+        recording.markers_received[marker.sender as usize] = true;
+        if !recording.markers_received.iter().all(|&m| m) {
+            return (markers, Vec::new());
+        }
+
+        let recording = self.recording.take().unwrap();
+        let chunks = self.chunk_record(&recording);
+        (markers, chunks)
+    }
+
+    // Regular messages are part of a channel's state while that channel is being recorded
+    pub fn record(&mut self, message: &MessagePayload) {
+        if let Some(recording) = self.recording.as_mut() {
+            if !recording.markers_received[message.sender as usize] {
+                recording.record.channels[message.sender as usize].push(*message);
+            }
+        }
+    }
+
+    fn chunk_record(&self, recording: &Recording) -> Vec<MessagePayload> {
+        let bytes = serde_json::to_vec(&recording.record).expect("Failed to serialize record");
+        let mut chunks = Vec::new();
+        for chunk in bytes.chunks(CHUNK_BYTES) {
+            chunks.push(MessagePayload::new(
+                18,
+                chunk.len() as i32,
+                recording.id,
+                self.rank,
+                recording.initiator,
+                pack_chunk(chunk),
+            ));
+        }
+        chunks.push(MessagePayload::new(
+            19,
+            0,
+            recording.id,
+            self.rank,
+            recording.initiator,
+            VectorClock::default(),
+        ));
+        chunks
+    }
+
+    // Initiator side: reassembles the records and writes the artifact once all are in
+    pub fn on_chunk(&mut self, chunk: &MessagePayload) {
+        let collection = match self.collection.as_mut() {
+            Some(collection) if collection.id == chunk.invoker => collection,
+            _ => return,
+        };
+        let sender = chunk.sender as usize;
+        if chunk.message == 18 {
+            collection.buffers[sender].extend(unpack_chunk(chunk));
+            return;
+        }
+
+        let buffer = std::mem::take(&mut collection.buffers[sender]);
+        match serde_json::from_slice(&buffer) {
+            Ok(record) => collection.records[sender] = Some(record),
+            Err(e) => error!(sender, "corrupt snapshot record: {}", e),
+        }
+        if collection.records.iter().all(|r| r.is_some()) {
+            let collection = self.collection.take().unwrap();
+            let snapshot = GlobalSnapshot {
+                id: collection.id,
+                initiator: self.rank,
+                processes: collection.records.into_iter().flatten().collect(),
+            };
+            let path = GlobalSnapshot::path(&self.dir, snapshot.id);
+            let result = fs::create_dir_all(&self.dir).and_then(|_| {
+                fs::write(
+                    &path,
+                    serde_json::to_vec_pretty(&snapshot).map_err(io::Error::from)?,
+                )
+            });
+            match result {
+                Ok(()) => info!(
+                    id = snapshot.id,
+                    path = %path.display(),
+                    "wrote global snapshot"
+                ),
+                Err(e) => error!(id = snapshot.id, "failed to write global snapshot: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("async_queue_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn chunks_round_trip_multi_byte_text() {
+        // 3 and 4 byte characters straddle word and chunk boundaries
+        let text = "queue → état ✓ 🦀 ".repeat(20) + "end";
+        let bytes = text.as_bytes();
+        assert_ne!(bytes.len() % CHUNK_BYTES, 0);
+        let chunks: Vec<MessagePayload> = bytes
+            .chunks(CHUNK_BYTES)
+            .map(|chunk| MessagePayload::new(18, chunk.len() as i32, 1, 0, 0, pack_chunk(chunk)))
+            .collect();
+        let last = chunks.last().unwrap();
+        assert!((last.value as usize) < CHUNK_BYTES);
+        assert_eq!(last.time_stamp.size, (last.value as usize).div_ceil(4));
+        assert!(chunks[..chunks.len() - 1]
+            .iter()
+            .all(|c| c.time_stamp.size == CHUNK_BYTES / 4));
+
+        // Chunks cross the wire as JSON
+        let mut reassembled = Vec::new();
+        for chunk in chunks {
+            let json = serde_json::to_string(&chunk).unwrap();
+            reassembled.extend(unpack_chunk(&serde_json::from_str(&json).unwrap()));
+        }
+        assert_eq!(String::from_utf8(reassembled).unwrap(), text);
+    }
+
+    #[test]
+    fn in_flight_messages_are_recorded_until_the_channel_marker() {
+        let dir = scratch_dir("global_snapshot");
+        let config = Config::default();
+        let mut nodes: Vec<ProcessData> = (0..2)
+            .map(|rank| ProcessData::new(rank, 2, &config))
+            .collect();
+        nodes[1]
+            .local_queue
+            .insert((5, 1, VectorClock::try_from(vec![0, 1]).unwrap()));
+        let mut initiator = GlobalSnapshotter::new(0, 2, dir.clone());
+        let mut peer = GlobalSnapshotter::new(1, 2, dir.clone());
+
+        let markers_0 = initiator.start(7, &nodes[0], &[]);
+        assert_eq!(markers_0.len(), 2);
+        // Rank 1 sent an EnqAck before it saw the marker, so it is in flight on 1 -> 0
+        let in_flight = MessagePayload::new(2, 3, 0, 1, 0, VectorClock::new(2));
+        initiator.record(&in_flight);
+        // Rank 0's own channel closes with its marker
+        let (markers, chunks) = initiator.on_marker(&markers_0[0], &nodes[0], &[]);
+        assert!(markers.is_empty() && chunks.is_empty());
+
+        let (markers_1, chunks) = peer.on_marker(&markers_0[1], &nodes[1], &[]);
+        assert_eq!(markers_1.len(), 2);
+        assert!(chunks.is_empty());
+        let (_, peer_chunks) = peer.on_marker(&markers_1[1], &nodes[1], &[]);
+        assert!(peer_chunks.len() > 2);
+        assert_eq!(peer_chunks.last().unwrap().message, 19);
+
+        let (_, own_chunks) = initiator.on_marker(&markers_1[0], &nodes[0], &[]);
+        // After the marker the channel is no longer recorded
+        initiator.record(&MessagePayload::new(2, 4, 0, 1, 0, VectorClock::new(2)));
+        for chunk in peer_chunks.iter().chain(own_chunks.iter()) {
+            initiator.on_chunk(chunk);
+        }
+
+        let json = fs::read(GlobalSnapshot::path(&dir, 7)).unwrap();
+        let snapshot: GlobalSnapshot = serde_json::from_slice(&json).unwrap();
+        assert_eq!(snapshot.id, 7);
+        let ranks: Vec<Rank> = snapshot.processes.iter().map(|p| p.rank).collect();
+        assert_eq!(ranks, vec![0, 1]);
+        let channels = &snapshot.processes[0].channels;
+        assert_eq!(channels[1].len(), 1);
+        assert_eq!(channels[1][0].value, 3);
+        assert!(channels[0].is_empty());
+        assert_eq!(snapshot.processes[1].state.local_queue.len(), 1);
+        assert_eq!(snapshot.processes[1].state.local_queue[0].0, 5);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
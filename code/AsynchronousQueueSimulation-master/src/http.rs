@@ -0,0 +1,493 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, info, info_span, warn};
+
+use crate::admin::AdminCommand;
+use crate::metrics::ConnectionGuard;
+use crate::protocol::{self, ClientContext, ErrorCode, ProtocolError};
+
+// Optional HTTP front-end, one request per connection:
+//
+//   POST /enqueue  {"value":5}  ->  200 {}
+//   POST /dequeue               ->  200 {"value":5} (null when the queue was empty)
+//   GET  /status                ->  200 {"rank":0,"counters":{..},"suspected":[],"stalled":[]}
+//
+// Enqueue and Dequeue go through the same channel as the client port and the response is
+// written once the replicated operation has returned. Errors carry the JSON protocol's
+// `{"code":..,"message":..}` body, and a 405 names the endpoint's method in `Allow`.
+const MAX_BODY: usize = 64 * 1024;
+// Limits on the request line and headers, which are read before anything is checked
+const MAX_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 64;
+// A client that stops reading or writing mid-request loses its connection after this
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+// Connections served at once; more are answered 503 straight away
+const MAX_CONNECTIONS: usize = 256;
+
+#[derive(Debug, Deserialize)]
+struct EnqueueBody {
+    value: i32,
+}
+
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+fn status_code(code: ErrorCode) -> u16 {
+    match code {
+        ErrorCode::ParseError | ErrorCode::InvalidArgument | ErrorCode::UnsupportedVersion => 400,
+        ErrorCode::UnknownOp => 404,
+        ErrorCode::MethodNotAllowed => 405,
+        ErrorCode::Rejected => 409,
+        ErrorCode::Unavailable => 503,
+        ErrorCode::Timeout => 504,
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Error",
+    }
+}
+
+// Reads one line of at most MAX_LINE bytes, returning false if it was cut off
+fn read_limited_line(reader: &mut impl BufRead, line: &mut String) -> std::io::Result<bool> {
+    line.clear();
+    reader.take(MAX_LINE as u64).read_line(line)?;
+    Ok(line.ends_with('\n') || line.len() < MAX_LINE)
+}
+
+pub fn read_request(stream: &TcpStream) -> Result<HttpRequest, (u16, ProtocolError)> {
+    let bad_request = |message: &str| {
+        (
+            400,
+            ProtocolError::new(ErrorCode::ParseError, message.to_string()),
+        )
+    };
+    let too_large = |status: u16, message: String| {
+        (
+            status,
+            ProtocolError::new(ErrorCode::InvalidArgument, message),
+        )
+    };
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    let complete = read_limited_line(&mut reader, &mut line)
+        .map_err(|_| bad_request("Failed to read request line"))?;
+    if !complete {
+        return Err(too_large(
+            414,
+            format!("Request line longer than {} bytes", MAX_LINE),
+        ));
+    }
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Err(bad_request("Malformed request line")),
+    };
+
+    let mut content_length = 0;
+    let mut headers = 0;
+    loop {
+        let complete = read_limited_line(&mut reader, &mut line)
+            .map_err(|_| bad_request("Failed to read headers"))?;
+        if !complete {
+            return Err(too_large(
+                431,
+                format!("Header longer than {} bytes", MAX_LINE),
+            ));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return Err(too_large(431, format!("More than {} headers", MAX_HEADERS)));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| bad_request("Invalid Content-Length"))?;
+            }
+        }
+    }
+    if content_length > MAX_BODY {
+        return Err((
+            413,
+            ProtocolError::new(
+                ErrorCode::InvalidArgument,
+                format!("Body larger than {} bytes", MAX_BODY),
+            ),
+        ));
+    }
+    let mut body = vec![0; content_length];
+    reader
+        .read_exact(&mut body)
+        .map_err(|_| bad_request("Body shorter than Content-Length"))?;
+    Ok(HttpRequest { method, path, body })
+}
+
+// Bounds how long an accepted connection can block its thread on a slow client
+pub fn set_timeouts(stream: &TcpStream) {
+    let _ = stream.set_read_timeout(Some(IO_TIMEOUT));
+    let _ = stream.set_write_timeout(Some(IO_TIMEOUT));
+}
+
+pub fn write_response(stream: &TcpStream, status: u16, content_type: &str, body: &str) {
+    write_response_with_headers(stream, status, content_type, &[], body);
+}
+
+pub fn write_response_with_headers(
+    mut stream: &TcpStream,
+    status: u16,
+    content_type: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) {
+    let extra: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect();
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        content_type,
+        body.len(),
+        extra,
+        body
+    );
+}
+
+// Query strings are not used by any endpoint
+fn endpoint(request: &HttpRequest) -> &str {
+    request.path.split('?').next().unwrap_or("")
+}
+
+// The `Allow` header of an endpoint, None for unknown paths
+fn allowed_methods(path: &str) -> Option<&'static str> {
+    match path {
+        "/enqueue" | "/dequeue" => Some("POST"),
+        "/status" => Some("GET"),
+        _ => None,
+    }
+}
+
+fn route(ctx: &ClientContext, request: &HttpRequest) -> Result<Value, (u16, ProtocolError)> {
+    let error = |e: ProtocolError| (status_code(e.code), e);
+    let path = endpoint(request);
+    match (request.method.as_str(), path) {
+        ("POST", "/enqueue") => {
+            let body: EnqueueBody = serde_json::from_slice(&request.body).map_err(|e| {
+                error(ProtocolError::new(
+                    ErrorCode::InvalidArgument,
+                    e.to_string(),
+                ))
+            })?;
+            let message = protocol::invocation(0, body.value, ctx.rank);
+            protocol::outcome_result(protocol::invoke(ctx, message).map_err(error)?).map_err(error)
+        }
+        ("POST", "/dequeue") => {
+            let message = protocol::invocation(3, 0, ctx.rank);
+            protocol::outcome_result(protocol::invoke(ctx, message).map_err(error)?).map_err(error)
+        }
+        ("GET", "/status") => {
+            let counters = protocol::admin(ctx, AdminCommand::Counters).map_err(error)?;
+            let health = ctx.health.lock().unwrap().clone();
+            Ok(json!({
+                "rank": ctx.rank,
+                "counters": counters,
+                "suspected": health.suspected,
+                "stalled": health.stalled,
+            }))
+        }
+        (_, path) if allowed_methods(path).is_some() => Err((
+            405,
+            ProtocolError::new(
+                ErrorCode::MethodNotAllowed,
+                format!("{} is not allowed on {}", request.method, path),
+            ),
+        )),
+        _ => Err((
+            404,
+            ProtocolError::new(ErrorCode::UnknownOp, format!("No endpoint {}", path)),
+        )),
+    }
+}
+
+fn handle_connection(stream: TcpStream, ctx: ClientContext) {
+    let peer = stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    let _span = info_span!("client", rank = ctx.rank, %peer).entered();
+    let _connection = ConnectionGuard::new(&ctx.connections);
+    set_timeouts(&stream);
+    let (result, allow) = match read_request(&stream) {
+        Ok(request) => {
+            debug!(method = %request.method, path = %request.path, "HTTP request");
+            (route(&ctx, &request), allowed_methods(endpoint(&request)))
+        }
+        Err(e) => (Err(e), None),
+    };
+    match result {
+        Ok(body) => write_response(&stream, 200, "application/json", &body.to_string()),
+        Err((status, error)) => {
+            // A 405 must say which methods the endpoint takes
+            let allow = allow
+                .filter(|_| status == 405)
+                .map(|methods| ("Allow", methods));
+            write_response_with_headers(
+                &stream,
+                status,
+                "application/json",
+                allow.as_slice(),
+                &json!({ "error": error }).to_string(),
+            )
+        }
+    }
+}
+
+pub fn start_http_server(port: u16, ctx: ClientContext) -> std::io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    info!(rank = ctx.rank, port, "HTTP server listening");
+
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if active.load(Ordering::Relaxed) >= MAX_CONNECTIONS {
+                    warn!(rank = ctx.rank, "too many HTTP connections, refusing one");
+                    set_timeouts(&stream);
+                    let error = ProtocolError::new(
+                        ErrorCode::Unavailable,
+                        format!("More than {} open connections", MAX_CONNECTIONS),
+                    );
+                    write_response(
+                        &stream,
+                        503,
+                        "application/json",
+                        &json!({ "error": error }).to_string(),
+                    );
+                    continue;
+                }
+                active.fetch_add(1, Ordering::Relaxed);
+                let ctx = ctx.clone();
+                let active = active.clone();
+                thread::spawn(move || {
+                    handle_connection(stream, ctx);
+                    active.fetch_sub(1, Ordering::Relaxed);
+                });
+            }
+            Err(e) => {
+                warn!(rank = ctx.rank, "failed to accept HTTP client: {}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_payload::MessagePayload;
+    use crate::process_data::OpOutcome;
+    use crate::protocol::stub;
+    use mpi::Rank;
+
+    // Sends `raw` over a loopback connection and parses what arrives
+    fn parse(raw: Vec<u8>) -> Result<HttpRequest, (u16, ProtocolError)> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let _ = stream.write_all(&raw);
+            stream
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let request = read_request(&stream);
+        drop(client.join());
+        request
+    }
+
+    fn status(raw: Vec<u8>) -> u16 {
+        parse(raw).err().map_or(200, |(status, _)| status)
+    }
+
+    #[test]
+    fn reads_a_request_with_a_body() {
+        let request =
+            parse(b"POST /enqueue HTTP/1.1\r\nContent-Length: 11\r\n\r\n{\"value\":5}".to_vec())
+                .unwrap();
+        assert_eq!(
+            (request.method.as_str(), request.path.as_str()),
+            ("POST", "/enqueue")
+        );
+        assert_eq!(request.body, b"{\"value\":5}");
+    }
+
+    #[test]
+    fn rejects_oversized_request_lines_and_headers() {
+        let long_path = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE));
+        assert_eq!(status(long_path.into_bytes()), 414);
+
+        let long_header = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_LINE));
+        assert_eq!(status(long_header.into_bytes()), 431);
+
+        let many_headers = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X: 1\r\n".repeat(MAX_HEADERS + 1)
+        );
+        assert_eq!(status(many_headers.into_bytes()), 431);
+
+        let enough_headers = format!("GET / HTTP/1.1\r\n{}\r\n", "X: 1\r\n".repeat(MAX_HEADERS));
+        assert_eq!(status(enough_headers.into_bytes()), 200);
+    }
+
+    #[test]
+    fn wrong_methods_get_their_own_error_code() {
+        assert_eq!(status_code(ErrorCode::MethodNotAllowed), 405);
+        assert_eq!(
+            serde_json::to_value(ErrorCode::MethodNotAllowed).unwrap(),
+            json!("method_not_allowed")
+        );
+    }
+
+    fn request(method: &str, path: &str, body: &str) -> HttpRequest {
+        HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    // Serves one connection carrying `raw` and returns everything written back
+    fn exchange(ctx: ClientContext, raw: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let raw = raw.to_string();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(raw.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+        let (stream, _) = listener.accept().unwrap();
+        handle_connection(stream, ctx);
+        client.join().unwrap()
+    }
+
+    fn answer(message: &MessagePayload) -> Option<OpOutcome> {
+        match message.message {
+            0 => Some(OpOutcome::Enqueued),
+            _ => Some(OpOutcome::Dequeued(Some(5))),
+        }
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        let (ctx, received) = stub::context(answer);
+        let (status, error) = route(&ctx, &request("GET", "/queue", "")).unwrap_err();
+        assert_eq!(status, 404);
+        assert_eq!(error.code, ErrorCode::UnknownOp);
+        let (status, _) = route(&ctx, &request("POST", "/enqueue/5", "")).unwrap_err();
+        assert_eq!(status, 404);
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn wrong_methods_are_answered_with_the_allowed_ones() {
+        let (ctx, received) = stub::context(answer);
+        for (method, path) in [
+            ("GET", "/enqueue"),
+            ("PUT", "/dequeue"),
+            ("POST", "/status"),
+        ] {
+            let (status, error) = route(&ctx, &request(method, path, "")).unwrap_err();
+            assert_eq!(status, 405);
+            assert_eq!(error.code, ErrorCode::MethodNotAllowed);
+        }
+        assert!(received.lock().unwrap().is_empty());
+
+        let response = exchange(ctx.clone(), "GET /enqueue?value=5 HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(response.contains("\r\nAllow: POST\r\n"));
+        let response = exchange(ctx.clone(), "DELETE /status HTTP/1.1\r\n\r\n");
+        assert!(response.contains("\r\nAllow: GET\r\n"));
+        // Only a 405 carries it
+        let response = exchange(ctx, "GET /nowhere HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(!response.contains("Allow:"));
+    }
+
+    #[test]
+    fn queue_operations_map_onto_protocol_results() {
+        let (ctx, received) = stub::context(answer);
+        let enqueued = route(&ctx, &request("POST", "/enqueue", r#"{"value":5}"#)).unwrap();
+        assert_eq!(enqueued, json!({}));
+        let dequeued = route(&ctx, &request("POST", "/dequeue", "")).unwrap();
+        assert_eq!(dequeued, json!({ "value": 5 }));
+        let invocations: Vec<(i32, i32, Rank)> = received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| (m.message, m.value, m.invoker))
+            .collect();
+        assert_eq!(invocations, vec![(0, 5, 2), (3, 0, 2)]);
+
+        let (status, error) =
+            route(&ctx, &request("POST", "/enqueue", r#"{"value":"five"}"#)).unwrap_err();
+        assert_eq!((status, error.code), (400, ErrorCode::InvalidArgument));
+
+        let response = exchange(
+            ctx,
+            "POST /enqueue HTTP/1.1\r\nContent-Length: 11\r\n\r\n{\"value\":7}",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n{}"));
+    }
+
+    #[test]
+    fn empty_rejected_and_unanswered_operations() {
+        let (ctx, _) = stub::context(|message| match message.value {
+            0 => Some(OpOutcome::Dequeued(None)),
+            1 => Some(OpOutcome::Rejected(
+                "not a member of the current configuration",
+            )),
+            _ => None,
+        });
+        let empty = route(&ctx, &request("POST", "/dequeue", "")).unwrap();
+        assert_eq!(empty, json!({ "value": null }));
+
+        let (status, error) =
+            route(&ctx, &request("POST", "/enqueue", r#"{"value":1}"#)).unwrap_err();
+        assert_eq!((status, error.code), (409, ErrorCode::Rejected));
+
+        let (status, error) =
+            route(&ctx, &request("POST", "/enqueue", r#"{"value":2}"#)).unwrap_err();
+        assert_eq!((status, error.code), (504, ErrorCode::Timeout));
+    }
+}
@@ -0,0 +1,170 @@
+use mpi::Rank;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::message_payload::VectorClock;
+use crate::timestamp_order::{OrderKey, TimestampOrder};
+
+// Entries are keyed on (order key, insertion sequence) so that elements with equal keys keep
+// the order in which they were inserted, matching a sorted list insert.
+type QueueKey = (OrderKey, u64);
+
+#[derive(Clone, Default)]
+pub struct LocalQueue {
+    entries: BTreeMap<QueueKey, (i32, Rank, VectorClock)>,
+    next_seq: u64,
+    order: TimestampOrder,
+}
+
+impl LocalQueue {
+    pub fn new(order: TimestampOrder) -> Self {
+        LocalQueue {
+            entries: BTreeMap::new(),
+            next_seq: 0,
+            order,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // O(log n): equal timestamps are placed after the ones already present
+    pub fn insert(&mut self, value: (i32, Rank, VectorClock)) {
+        let key = self.order.key(&value.2, value.1);
+        self.entries.insert((key, self.next_seq), value);
+        self.next_seq += 1;
+    }
+
+    // Removes the oldest element ordered strictly before the operation (`ts`, `invoker`).
+    // Since the queue is ordered, that element can only be the front one, so this is O(log n).
+    pub fn dequeue_before(
+        &mut self,
+        ts: &VectorClock,
+        invoker: Rank,
+    ) -> Option<(i32, Rank, VectorClock)> {
+        let front = self.entries.first_entry()?;
+        if front.key().0 < self.order.key(ts, invoker) {
+            Some(front.remove())
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (i32, Rank, VectorClock)> + '_ {
+        self.entries.values().copied()
+    }
+}
+
+impl fmt::Debug for LocalQueue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp::Ordering;
+    use std::collections::VecDeque;
+
+    // The sorted list the queue replaced: insert after every element not ordered after the
+    // new one, dequeue the first element ordered strictly before the operation
+    struct ListQueue {
+        entries: VecDeque<(i32, Rank, VectorClock)>,
+        order: TimestampOrder,
+    }
+
+    impl ListQueue {
+        fn insert(&mut self, value: (i32, Rank, VectorClock)) {
+            let order = self.order;
+            let position = self
+                .entries
+                .iter()
+                .position(|x| order.compare(&value.2, value.1, &x.2, x.1) == Ordering::Less)
+                .unwrap_or(self.entries.len());
+            self.entries.insert(position, value);
+        }
+
+        fn dequeue_before(
+            &mut self,
+            ts: &VectorClock,
+            invoker: Rank,
+        ) -> Option<(i32, Rank, VectorClock)> {
+            let order = self.order;
+            let mut oldest: Option<usize> = None;
+            for (index, x) in self.entries.iter().enumerate() {
+                let before = order.compare(&x.2, x.1, ts, invoker) == Ordering::Less;
+                let older = oldest.is_none_or(|o| {
+                    let y = &self.entries[o];
+                    order.compare(&x.2, x.1, &y.2, y.1) == Ordering::Less
+                });
+                if before && older {
+                    oldest = Some(index);
+                }
+            }
+            self.entries.remove(oldest?)
+        }
+    }
+
+    // Small clock entries and few invokers, so equal timestamps from different invokers and
+    // equal (timestamp, invoker) pairs both come up often
+    fn random_clock(state: &mut u64) -> VectorClock {
+        let entries: Vec<i32> = (0..3).map(|_| next(state, 3) as i32).collect();
+        VectorClock::try_from(entries).unwrap()
+    }
+
+    fn next(state: &mut u64, n: u64) -> u64 {
+        *state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (*state >> 33) % n
+    }
+
+    #[test]
+    fn matches_the_sorted_list_it_replaced() {
+        for order in [TimestampOrder::Lexicographic, TimestampOrder::SumThenRank] {
+            for seed in 0..200 {
+                let mut state = seed;
+                let mut queue = LocalQueue::new(order);
+                let mut list = ListQueue {
+                    entries: VecDeque::new(),
+                    order,
+                };
+                for step in 0..60 {
+                    let ts = random_clock(&mut state);
+                    let invoker = next(&mut state, 3) as Rank;
+                    if next(&mut state, 3) == 0 {
+                        assert_eq!(
+                            queue.dequeue_before(&ts, invoker),
+                            list.dequeue_before(&ts, invoker),
+                            "{:?} seed {} step {}",
+                            order,
+                            seed,
+                            step
+                        );
+                    } else {
+                        queue.insert((step, invoker, ts));
+                        list.insert((step, invoker, ts));
+                    }
+                    assert_eq!(queue.len(), list.entries.len());
+                    assert!(queue.iter().eq(list.entries.iter().copied()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn equal_timestamps_are_ordered_by_invoker_then_insertion() {
+        let ts = VectorClock::try_from(vec![1, 1]).unwrap();
+        let mut queue = LocalQueue::new(TimestampOrder::Lexicographic);
+        queue.insert((1, 1, ts));
+        queue.insert((2, 0, ts));
+        queue.insert((3, 1, ts));
+        assert_eq!(queue.dequeue_before(&ts, 0), None);
+        let values: Vec<i32> = std::iter::from_fn(|| queue.dequeue_before(&ts, 2))
+            .map(|e| e.0)
+            .collect();
+        assert_eq!(values, vec![2, 1, 3]);
+    }
+}
@@ -0,0 +1,49 @@
+use mpi::Rank;
+use std::str::FromStr;
+use tracing::{field, info_span, Span};
+use tracing_subscriber::EnvFilter;
+
+use crate::config::Config;
+use crate::message_payload::VectorClock;
+
+// Leveled, structured diagnostics. Events on the MPI thread belong to a `process` span
+// carrying the rank and the current vector clock; client threads use a `client` span with
+// the rank and peer address. `--log-filter` takes `RUST_LOG` style directives, so protocol
+// tracing can be enabled for one module, e.g. `info,async_queue::process_data=trace`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format: {}", s)),
+        }
+    }
+}
+
+pub fn init(config: &Config) {
+    let filter = EnvFilter::try_new(&config.log_filter)
+        .unwrap_or_else(|e| panic!("Invalid log filter {}: {}", config.log_filter, e));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.log_format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+pub fn process_span(rank: Rank) -> Span {
+    info_span!("process", rank, ts = field::Empty)
+}
+
+// Updates the clock shown on the current process span
+pub fn record_clock(ts: &VectorClock) {
+    Span::current().record("ts", field::display(ts));
+}
@@ -1,19 +1,64 @@
 use message_payload::MessagePayload;
+use mpi::collective::SystemOperation;
 use mpi::traits::*;
-use std::io::{BufRead, BufReader};
+use mpi::Rank;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
 use std::net::{TcpListener, TcpStream};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::Arc;
 use std::thread;
+use std::time::Instant;
+use tracing::{debug, info, info_span, warn};
 
+use crate::admin::{AdminCommand, AdminRequest, OperationCounters};
+use crate::audit::DequeueAuditor;
+use crate::batch::{Outbox, Sends};
+use crate::complexity::MessageComplexity;
+use crate::config::Config;
+use crate::digest::DigestChecker;
+use crate::failure_detector::{FailureDetector, SharedHealth};
+use crate::global_snapshot::GlobalSnapshotter;
 use crate::message_payload::VectorClock;
-use crate::process_data::ProcessData;
+use crate::metrics::{ConnectionGuard, Metrics};
+use crate::process_data::{OpOutcome, ProcessData};
+use crate::protocol::{ClientContext, ClientRequest};
+use crate::shiviz::ShivizLog;
+use crate::snapshot::Snapshot;
+use crate::wal::{Recovered, WriteAheadLog};
 extern crate ctrlc;
+mod admin;
+mod audit;
+mod batch;
+mod complexity;
+mod config;
+mod digest;
+mod failure_detector;
+mod global_snapshot;
+mod http;
+mod local_queue;
+mod logging;
+mod membership;
 mod message_payload;
+mod metrics;
 mod process_data;
+mod protocol;
+mod repl;
+mod sequence;
+mod shiviz;
+mod snapshot;
+mod timestamp_order;
+mod wal;
 
-fn handle_client(stream: TcpStream, tx: Sender<MessagePayload>, rank: i32) {
+fn handle_client(mut stream: TcpStream, ctx: ClientContext) {
+    let _connection = ConnectionGuard::new(&ctx.connections);
+    let rank = ctx.rank;
+    let peer = stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    let _span = info_span!("client", rank, %peer).entered();
     let mut reader = BufReader::new(stream.try_clone().expect("Failed to clone stream"));
     let mut line = String::new();
 
@@ -24,44 +69,93 @@ fn handle_client(stream: TcpStream, tx: Sender<MessagePayload>, rank: i32) {
                 break;
             }
             Ok(_) => {
-                println!("Process {} received: {}", rank, line.trim());
+                debug!(line = line.trim(), "client request");
+                if line.trim_start().starts_with('{') {
+                    let response = protocol::handle_line(&ctx, line.trim());
+                    let _ = writeln!(stream, "{}", response);
+                    continue;
+                }
+                if let Ok(command) = line.trim().parse::<AdminCommand>() {
+                    let reply = protocol::admin(&ctx, command)
+                        .map(|reply| reply.to_string())
+                        .unwrap_or_else(|_| "{\"error\":\"no reply\"}".to_string());
+                    let _ = writeln!(stream, "{}", reply);
+                    continue;
+                }
                 // Attempt to parse the message
                 if let Some(message) = parse_message(&line) {
-                    println!("{:?}", message);
-                    tx.send(message)
+                    debug!(?message, "parsed legacy request");
+                    ctx.tx
+                        .send(ClientRequest {
+                            message,
+                            reply: None,
+                        })
                         .expect("Failed to send parsed message to MPI thread");
+                    // Legacy requests get no reply at all. Health warnings reach clients
+                    // of the JSON protocol, in the `warnings` of each response.
                 } else {
-                    println!("Failed to parse message at process {}: {}", rank, line);
+                    warn!(line = line.trim(), "failed to parse client request");
                 }
             }
             Err(e) => {
-                println!("Failed to read from client at process {}: {}", rank, e);
+                warn!("failed to read from client: {}", e);
                 break;
             }
         }
     }
 }
 
-fn start_server(port: u16, tx: Sender<MessagePayload>, rank: i32) -> std::io::Result<()> {
+fn start_server(port: u16, ctx: ClientContext) -> std::io::Result<()> {
     let listener = TcpListener::bind(("0.0.0.0", port))?;
-    println!("Process {} server listening on port {}", rank, port);
+    info!(rank = ctx.rank, port, "client server listening");
 
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
-                let tx = tx.clone();
+                let ctx = ctx.clone();
                 thread::spawn(move || {
-                    handle_client(stream, tx, rank);
+                    handle_client(stream, ctx);
                 });
             }
             Err(e) => {
-                println!("Failed to accept client at process {}: {}", rank, e);
+                warn!(rank = ctx.rank, "failed to accept client: {}", e);
             }
         }
     }
     Ok(())
 }
 
+// Records the operations that have returned and answers the clients waiting on them
+fn answer_clients(
+    process_data: &mut ProcessData,
+    waiting: &mut HashMap<i32, (Sender<OpOutcome>, Instant)>,
+    metrics: &mut Metrics,
+    complexity: &mut MessageComplexity,
+) {
+    for completion in std::mem::take(&mut process_data.completions) {
+        metrics.on_completion(&completion);
+        complexity.on_completion(&completion);
+        if let Some((reply, _)) = waiting.remove(&completion.op_id) {
+            let _ = reply.send(completion.outcome);
+        }
+    }
+}
+
+// One MPI send carrying every message of a frame, followed by the frame's ShiViz clocks
+// when tracing
+fn send_frame<C: Communicator>(
+    world: &C,
+    sends: &mut Sends,
+    receiver: Rank,
+    frame: Vec<MessagePayload>,
+    counters: &mut OperationCounters,
+    shiviz: Option<&mut ShivizLog>,
+) {
+    let clocks = shiviz.map(|log| log.frame_clocks(receiver, &frame));
+    sends.send(world, receiver, frame, clocks);
+    counters.frames_sent += 1;
+}
+
 fn parse_message(input: &str) -> Option<MessagePayload> {
     let mut process = None;
     let mut op = None;
@@ -93,13 +187,57 @@ fn parse_message(input: &str) -> Option<MessagePayload> {
 }
 
 fn main() {
+    let config = Config::from_args();
+    logging::init(&config);
+    if let Some(diagram) = &config.diagram {
+        sequence::run(diagram).expect("Failed to render sequence diagram");
+        return;
+    }
+    if let Some(size) = config.repl {
+        repl::run(size, &config);
+        return;
+    }
     let universe = mpi::initialize().unwrap();
 
     let world = universe.world();
     let size = world.size();
     let rank = world.rank();
+    // Every receive must fit the largest frame any rank sends
+    let mut batch_max = 0;
+    world.all_reduce_into(&config.batch_max, &mut batch_max, SystemOperation::max());
+    let _process = logging::process_span(rank).entered();
 
-    let mut process_data = ProcessData::new(rank, size);
+    let mut process_data = ProcessData::new(rank, size, &config);
+    let recovered = if config.restore {
+        wal::recover(&mut process_data, &config, rank).expect("Failed to recover replica")
+    } else {
+        let wal = config.wal_dir.as_ref().map(|dir| {
+            let path = WriteAheadLog::path(dir, rank);
+            // A fresh start discards any log left by an earlier run
+            let mut wal = WriteAheadLog::open(&path, 1).expect("Failed to open write-ahead log");
+            wal.truncate().expect("Failed to reset write-ahead log");
+            wal
+        });
+        Recovered {
+            wal,
+            resend: Vec::new(),
+            next_op_id: 0,
+        }
+    };
+    let mut wal = recovered.wal;
+    let mut failure_detector = FailureDetector::new(rank, size, &config, Instant::now());
+    let mut auditor = (config.auditor_rank == Some(rank)).then(DequeueAuditor::new);
+    let mut global_snapshotter =
+        GlobalSnapshotter::new(rank, size, config.global_snapshot_dir.clone());
+    let health = SharedHealth::default();
+    let mut counters = OperationCounters::default();
+    let mut metrics = Metrics::default();
+    let mut complexity = MessageComplexity::new(rank);
+    let mut digest_checker = DigestChecker::new(rank);
+    let mut shiviz = config
+        .shiviz_dir
+        .as_ref()
+        .map(|dir| ShivizLog::create(dir, rank, size).expect("Failed to create ShiViz log"));
 
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
@@ -107,16 +245,9 @@ fn main() {
     // Set up signal handler for Ctrl+C
     ctrlc::set_handler(move || {
         if rank == 0 {
-            println!(
-                r#"
-
-=====================================================================
-
-Termination request received, input Ctrl+C again to finalize shutdown...
-
-=====================================================================
-
-                "#
+            info!(
+                rank,
+                "termination request received, input Ctrl+C again to finalize shutdown"
             );
         }
         r.store(false, Ordering::SeqCst);
@@ -126,19 +257,46 @@ Termination request received, input Ctrl+C again to finalize shutdown...
     let base_port = 8000; // Base port number
     let port = base_port + rank as u16; // Unique port for each process
 
-    let (tx, rx): (Sender<MessagePayload>, Receiver<MessagePayload>) = mpsc::channel();
+    let (tx, rx): (Sender<ClientRequest>, Receiver<ClientRequest>) = mpsc::channel();
+    let (admin_tx, admin_rx): (Sender<AdminRequest>, Receiver<AdminRequest>) = mpsc::channel();
+    // Clients waiting on an operation invoked here, by op id, with when they started waiting
+    let mut waiting: HashMap<i32, (Sender<OpOutcome>, Instant)> = HashMap::new();
+    let mut next_op_id = recovered.next_op_id;
 
     // Start the server in a separate thread for each MPI process
-    let tx_clone = tx.clone();
+    let ctx = ClientContext {
+        rank,
+        tx: tx.clone(),
+        admin_tx,
+        health: health.clone(),
+        timeout: config.client_timeout,
+        connections: metrics.connections.clone(),
+    };
+    if let Some(metrics_base_port) = config.metrics_base_port {
+        let metrics_port = metrics_base_port + rank as u16;
+        let admin_tx = ctx.admin_tx.clone();
+        thread::spawn(move || {
+            metrics::start_metrics_server(metrics_port, rank, admin_tx).unwrap();
+        });
+    }
+    if let Some(http_base_port) = config.http_base_port {
+        let http_port = http_base_port + rank as u16;
+        let ctx = ctx.clone();
+        thread::spawn(move || {
+            http::start_http_server(http_port, ctx).unwrap();
+        });
+    }
     thread::spawn(move || {
-        start_server(port, tx_clone, rank).unwrap();
+        start_server(port, ctx).unwrap();
     });
 
-    let mut dyn_data_buffer: Vec<MessagePayload> = Vec::new();
-    dyn_data_buffer.push(MessagePayload::default());
-    let mut current_index = 0;
+    // Receives land in a buffer that holds the largest frame a sender may batch
+    let mut frame_buffer = vec![MessagePayload::default(); batch_max];
+    let mut outbox = Outbox::new(config.batch, config.batch_max);
+    let mut sends = Sends::default();
 
-    let mut msgs: Vec<MessagePayload> = Vec::new();
+    // Whatever the steps replayed on recovery produced goes out first
+    let mut msgs: Vec<MessagePayload> = recovered.resend;
 
     // Predefined messages to run on startup
     // Note: Order of execution is not guaranteed
@@ -151,62 +309,310 @@ Termination request received, input Ctrl+C again to finalize shutdown...
     msgs.push(MessagePayload::new(0, 70, 1, 1, 1, process_data.timestamp));
 
     loop {
-        if current_index < dyn_data_buffer.len() {
-            let recv_buf = &mut dyn_data_buffer[current_index];
-            // Initiate non-blocking receives within a scope
-            mpi::request::multiple_scope(1, |scope, coll| {
-                let request = world.any_process().immediate_receive_into(scope, recv_buf);
-                coll.add(request);
-
-                loop {
-                    // Check for ready receives
-                    match coll.test_any() {
-                        Some((_, status, result)) => {
-                            // Handle the completion here
-
-                            println!(
-                                "Process {} received {:?} from process {}",
-                                rank,
-                                result,
-                                status.source_rank(),
+        let recv_buf = &mut frame_buffer[..];
+        // Initiate non-blocking receives within a scope
+        mpi::request::multiple_scope(1, |scope, coll| {
+            let request = world.any_process().immediate_receive_into(scope, recv_buf);
+            coll.add(request);
+
+            loop {
+                // Check for ready receives
+                match coll.test_any() {
+                    Some((_, status, frame)) => {
+                        // Handle the completion here
+                        failure_detector.heard_from(status.source_rank(), Instant::now());
+                        let received =
+                            status.count(MessagePayload::equivalent_datatype().as_ref()) as usize;
+                        // The frame's ShiViz clocks follow it on their own tag. They are taken
+                        // before the next receive is posted, so it never matches one.
+                        let log_clocks: Vec<VectorClock> = if shiviz.is_some() {
+                            world
+                                .process_at_rank(status.source_rank())
+                                .receive_vec_with_tag(shiviz::CLOCK_TAG)
+                                .0
+                        } else {
+                            Vec::new()
+                        };
+                        for (i, result) in frame[..received].iter().enumerate() {
+                            counters.on_received(result);
+                            if result.message == 6 {
+                                // Heartbeats only carry liveness
+                                continue;
+                            }
+                            if let Some(log) = shiviz.as_mut() {
+                                log.on_receive(result, &log_clocks[i]);
+                            }
+                            match result.message {
+                                16 => {
+                                    // Global snapshot invoke: markers go out before anything else,
+                                    // behind whatever was already batched
+                                    for (receiver, frame) in outbox.drain() {
+                                        send_frame(
+                                            &world,
+                                            &mut sends,
+                                            receiver,
+                                            frame,
+                                            &mut counters,
+                                            shiviz.as_mut(),
+                                        );
+                                    }
+                                    for marker in
+                                        global_snapshotter.start(result.value, &process_data, &msgs)
+                                    {
+                                        if let Some(log) = shiviz.as_mut() {
+                                            log.on_send(&marker);
+                                        }
+                                        send_frame(
+                                            &world,
+                                            &mut sends,
+                                            marker.receiver,
+                                            vec![marker],
+                                            &mut counters,
+                                            shiviz.as_mut(),
+                                        );
+                                        counters.on_sent(&marker);
+                                    }
+                                    continue;
+                                }
+                                17 => {
+                                    let (markers, chunks) =
+                                        global_snapshotter.on_marker(result, &process_data, &msgs);
+                                    for (receiver, frame) in outbox.drain() {
+                                        send_frame(
+                                            &world,
+                                            &mut sends,
+                                            receiver,
+                                            frame,
+                                            &mut counters,
+                                            shiviz.as_mut(),
+                                        );
+                                    }
+                                    for marker in markers {
+                                        if let Some(log) = shiviz.as_mut() {
+                                            log.on_send(&marker);
+                                        }
+                                        send_frame(
+                                            &world,
+                                            &mut sends,
+                                            marker.receiver,
+                                            vec![marker],
+                                            &mut counters,
+                                            shiviz.as_mut(),
+                                        );
+                                        counters.on_sent(&marker);
+                                    }
+                                    msgs.extend(chunks);
+                                    continue;
+                                }
+                                18 | 19 => {
+                                    global_snapshotter.on_chunk(result);
+                                    continue;
+                                }
+                                20 => {
+                                    if let Some(auditor) = auditor.as_mut() {
+                                        auditor
+                                            .on_report(result, process_data.executing_replicas());
+                                        counters.audited_dequeues = auditor.audited;
+                                        counters.audit_divergences = auditor.divergences;
+                                    }
+                                    continue;
+                                }
+                                21 => {
+                                    digest_checker.on_remote(result);
+                                    counters.digest_divergences = digest_checker.divergences;
+                                    continue;
+                                }
+                                _ => global_snapshotter.record(result),
+                            }
+
+                            debug!(
+                                kind = result.kind(),
+                                value = result.value,
+                                invoker = result.invoker,
+                                sender = status.source_rank(),
+                                msg_ts = %result.time_stamp,
+                                "received"
                             );
 
+                            complexity.on_received(result);
                             process_data.message_history.push(*result);
+                            if let Some(wal) = wal.as_mut() {
+                                process_data.applied_lsn = wal
+                                    .append(result)
+                                    .expect("Failed to append to write-ahead log");
+                            }
                             for msg in process_data.execute_locally(*result) {
                                 msgs.push(msg);
                             }
-                            break; // exit only when a receive has been processed
-                        }
-                        // While waiting for receives, try for external messages
-                        _ => {
-                            while let Ok(data) = rx.try_recv() {
-                                // Echo or process the message further, here we just send to the next process in a simple ring
-                                msgs.push(data);
+                            answer_clients(
+                                &mut process_data,
+                                &mut waiting,
+                                &mut metrics,
+                                &mut complexity,
+                            );
+                            let executed = std::mem::take(&mut process_data.executed_dequeues);
+                            complexity.on_executed(&executed);
+                            if let Some(log) = shiviz.as_mut() {
+                                if result.message == 1 {
+                                    log.on_apply(format!(
+                                        "enqueue value={} invoker={}",
+                                        result.value, result.invoker
+                                    ));
+                                }
+                                log.on_dequeues(&executed);
+                            }
+                            if let Some(auditor_rank) = config.auditor_rank {
+                                msgs.extend(audit::reports(executed, rank, auditor_rank));
+                            }
+                            for (count, chain) in
+                                std::mem::take(&mut process_data.digest.checkpoints)
+                            {
+                                msgs.extend(digest_checker.on_local(
+                                    count,
+                                    chain,
+                                    &process_data.membership.ranks(),
+                                ));
                             }
-                            // Send all avaliable messages, skipping invocations if a processes is
-                            // currently Enq/Deq
-                            let mut i = 0;
-                            while i < msgs.len() {
-                                if msgs[i].sender == rank
-                                    && !(msgs[i].message == 0 && process_data.locked)
+                            counters.digest_divergences = digest_checker.divergences;
+                            if let Some(dir) = &config.snapshot_dir {
+                                if process_data
+                                    .message_history
+                                    .len()
+                                    .is_multiple_of(config.snapshot_every)
                                 {
-                                    if msgs[i].message == 0 || msgs[i].message == 3 {
-                                        process_data.locked = true;
+                                    let path = Snapshot::path(dir, rank);
+                                    match process_data.snapshot().save(&path) {
+                                        // The snapshot now covers every logged message
+                                        Ok(()) => {
+                                            if let Some(wal) = wal.as_mut() {
+                                                wal.truncate()
+                                                    .expect("Failed to truncate write-ahead log");
+                                            }
+                                        }
+                                        Err(e) => warn!("failed to write snapshot: {}", e),
                                     }
-                                    world.process_at_rank(msgs[i].receiver).send(&msgs[i]);
+                                }
+                            }
+                        }
+                        break; // exit only when a receive has been processed
+                    }
+                    // While waiting for receives, try for external messages
+                    _ => {
+                        sends.progress(&world);
+                        while let Ok(request) = rx.try_recv() {
+                            let mut data = request.message;
+                            if let Some(reply) = request.reply {
+                                data = data.with_op_id(next_op_id);
+                                waiting.insert(next_op_id, (reply, Instant::now()));
+                                next_op_id += 1;
+                            }
+                            msgs.push(data);
+                        }
+                        // A client stops waiting after the client timeout, which closes its
+                        // reply channel, so its entry can go
+                        waiting.retain(|_, (_, since)| since.elapsed() < config.client_timeout);
+                        while let Ok(request) = admin_rx.try_recv() {
+                            let outbound: Vec<MessagePayload> = msgs
+                                .iter()
+                                .chain(outbox.pending())
+                                .chain(sends.held())
+                                .copied()
+                                .collect();
+                            let reply = admin::answer(
+                                request.command,
+                                rank,
+                                &process_data,
+                                &outbound,
+                                &counters,
+                                &complexity,
+                                &metrics,
+                            );
+                            let _ = request.reply.send(reply);
+                        }
+                        // Refresh the health report once per heartbeat round, or as soon
+                        // as the set of suspected ranks changes
+                        let now = Instant::now();
+                        let heartbeats =
+                            failure_detector.heartbeats_due(process_data.timestamp, now);
+                        let refresh = !heartbeats.is_empty();
+                        msgs.extend(heartbeats);
+                        let suspicions_changed = failure_detector.update_suspicions(now);
+                        if suspicions_changed {
+                            msgs.extend(
+                                process_data.set_suspected(&failure_detector.suspected_ranks()),
+                            );
+                            answer_clients(
+                                &mut process_data,
+                                &mut waiting,
+                                &mut metrics,
+                                &mut complexity,
+                            );
+                            let executed = std::mem::take(&mut process_data.executed_dequeues);
+                            complexity.on_executed(&executed);
+                            if let Some(log) = shiviz.as_mut() {
+                                log.on_dequeues(&executed);
+                            }
+                            if let Some(auditor_rank) = config.auditor_rank {
+                                msgs.extend(audit::reports(executed, rank, auditor_rank));
+                            }
+                        }
+                        if suspicions_changed || refresh {
+                            let suspected = failure_detector.suspected_ranks();
+                            let stalled = process_data.stalled_operations(&suspected);
+                            let mut health = health.lock().unwrap();
+                            for op in stalled.iter().filter(|op| !health.stalled.contains(op)) {
+                                warn!(operation = %op, "stalled");
+                            }
+                            health.suspected = suspected;
+                            health.stalled = stalled;
+                        }
+                        // Send all avaliable messages, skipping invocations if a processes is
+                        // currently Enq/Deq
+                        let mut i = 0;
+                        while i < msgs.len() {
+                            if msgs[i].sender == rank
+                                && !(msgs[i].message == 0 && process_data.locked)
+                            {
+                                if msgs[i].message == 0 || msgs[i].message == 3 {
+                                    process_data.locked = true;
+                                }
+                                if msgs[i].message != 6 {
+                                    if let Some(log) = shiviz.as_mut() {
+                                        log.on_send(&msgs[i]);
+                                    }
+                                }
+                                counters.on_sent(&msgs[i]);
+                                complexity.on_sent(&msgs[i]);
 
-                                    // Remove the message from the list after processing
-                                    msgs.remove(i);
-                                } else {
-                                    i += 1;
+                                // Remove the message from the list after processing
+                                if let Some((receiver, frame)) = outbox.push(msgs.remove(i)) {
+                                    send_frame(
+                                        &world,
+                                        &mut sends,
+                                        receiver,
+                                        frame,
+                                        &mut counters,
+                                        shiviz.as_mut(),
+                                    );
                                 }
+                            } else {
+                                i += 1;
                             }
                         }
+                        complexity.end_step();
+                        for (receiver, frame) in outbox.due() {
+                            send_frame(
+                                &world,
+                                &mut sends,
+                                receiver,
+                                frame,
+                                &mut counters,
+                                shiviz.as_mut(),
+                            );
+                        }
                     }
                 }
-            });
-            dyn_data_buffer.push(MessagePayload::default());
-            current_index += 1;
-        }
+            }
+        });
     }
 }
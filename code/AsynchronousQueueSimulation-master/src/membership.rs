@@ -0,0 +1,83 @@
+use mpi::Rank;
+use std::collections::VecDeque;
+
+use crate::message_payload::MessagePayload;
+
+// Rank that serializes every reconfiguration. It is a member of every configuration and
+// cannot leave.
+pub const COORDINATOR: Rank = 0;
+
+// A single membership change: `joining` is false when `subject` leaves
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ViewChange {
+    pub joining: bool,
+    pub subject: Rank,
+}
+
+impl ViewChange {
+    pub fn from_payload(payload: &MessagePayload) -> Self {
+        ViewChange {
+            joining: payload.value == 1,
+            subject: payload.invoker,
+        }
+    }
+
+    pub fn value(&self) -> i32 {
+        self.joining as i32
+    }
+}
+
+// Active configuration of the cluster. MPI fixes the number of processes at launch, so
+// `world_size` is the capacity and only the ranks marked in `members` take part in
+// operations. Spare ranks can join later and members can leave.
+//
+// Reconfiguration is stop-the-world: the coordinator announces a ViewChange, every member
+// defers new invocations and acknowledges once its own operations and every Dequeue it
+// knows about have completed. With all acknowledgements in, no operation is in flight, so
+// the coordinator's replica can be transferred to a joining process and the new
+// configuration committed at the same point of every replica's operation sequence.
+pub struct Membership {
+    pub members: Vec<bool>,
+    pub reconfiguring: bool,
+    pub ack_sent: bool,
+    pub view_acks: i32,
+    pub current_change: Option<ViewChange>,
+    pub queued_changes: VecDeque<ViewChange>,
+    pub deferred: Vec<MessagePayload>,
+}
+
+impl Membership {
+    pub fn new(size: i32, initial_members: i32) -> Self {
+        Membership {
+            members: (0..size).map(|r| r < initial_members).collect(),
+            reconfiguring: false,
+            ack_sent: false,
+            view_acks: 0,
+            current_change: None,
+            queued_changes: VecDeque::new(),
+            deferred: Vec::new(),
+        }
+    }
+
+    pub fn is_member(&self, rank: Rank) -> bool {
+        self.members[rank as usize]
+    }
+
+    pub fn ranks(&self) -> Vec<Rank> {
+        (0..self.members.len() as Rank)
+            .filter(|&r| self.is_member(r))
+            .collect()
+    }
+
+    pub fn count(&self) -> i32 {
+        self.members.iter().filter(|&&m| m).count() as i32
+    }
+
+    pub fn apply(&mut self, change: ViewChange) {
+        self.members[change.subject as usize] = change.joining;
+        self.reconfiguring = false;
+        self.ack_sent = false;
+        self.view_acks = 0;
+        self.current_change = None;
+    }
+}
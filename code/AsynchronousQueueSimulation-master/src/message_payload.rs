@@ -1,13 +1,25 @@
 use memoffset::offset_of;
 use mpi::datatype::{Equivalence, UserDatatype};
 use mpi::Rank;
+use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::mem::size_of;
 use std::{fmt, usize};
 
-const MAX_BUFFER_SIZE: usize = 32; // Upper limit on the number of processes in the system
+pub(crate) const MAX_BUFFER_SIZE: usize = 32; // Upper limit on the number of processes in the system
 
-#[derive(Copy, Clone)]
+// Causal relation between two vector clocks, as opposed to the total lexicographic order
+// used to linearize operations
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum CausalOrder {
+    Before,
+    After,
+    Equal,
+    Concurrent,
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+#[serde(into = "Vec<i32>", try_from = "Vec<i32>")]
 pub(crate) struct VectorClock {
     pub clock: [i32; MAX_BUFFER_SIZE],
     pub size: usize, // Tracks the number of active elements in `clock`
@@ -21,19 +33,52 @@ impl VectorClock {
         }
     }
 
+    // Lexicographic order on the clock entries, used as the total (tie-breaking) order on
+    // operation timestamps. This is also what `Ord` and `PartialOrd` report. Entries past a
+    // clock's size are zero, so clocks of different sizes compare consistently both ways.
     pub fn compare(&self, other: &Self) -> Ordering {
-        for i in 0..self.size {
+        for i in 0..self.size.max(other.size) {
             if self.clock[i] != other.clock[i] {
                 return self.clock[i].cmp(&other.clock[i]);
             }
         }
         Ordering::Equal
     }
+
+    // Element-wise comparison: `Before` if every entry is <= the other's (and they differ),
+    // `Concurrent` if each clock has an entry larger than the other's
+    pub fn causal_cmp(&self, other: &Self) -> CausalOrder {
+        let mut less = false;
+        let mut greater = false;
+        for i in 0..self.size.max(other.size) {
+            match self.clock[i].cmp(&other.clock[i]) {
+                Ordering::Less => less = true,
+                Ordering::Greater => greater = true,
+                Ordering::Equal => {}
+            }
+        }
+        match (less, greater) {
+            (false, false) => CausalOrder::Equal,
+            (true, false) => CausalOrder::Before,
+            (false, true) => CausalOrder::After,
+            (true, true) => CausalOrder::Concurrent,
+        }
+    }
+
+    pub fn happens_before(&self, other: &Self) -> bool {
+        self.causal_cmp(other) == CausalOrder::Before
+    }
+}
+
+impl Ord for VectorClock {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.compare(other)
+    }
 }
 
 impl PartialOrd for VectorClock {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.compare(other))
+        Some(self.cmp(other))
     }
 }
 
@@ -43,6 +88,8 @@ impl PartialEq for VectorClock {
     }
 }
 
+impl Eq for VectorClock {}
+
 impl Default for VectorClock {
     fn default() -> Self {
         Self {
@@ -52,6 +99,30 @@ impl Default for VectorClock {
     }
 }
 
+// Only the active entries are serialized
+impl From<VectorClock> for Vec<i32> {
+    fn from(vc: VectorClock) -> Self {
+        vc.clock[..vc.size].to_vec()
+    }
+}
+
+impl TryFrom<Vec<i32>> for VectorClock {
+    type Error = String;
+
+    fn try_from(entries: Vec<i32>) -> Result<Self, Self::Error> {
+        if entries.len() > MAX_BUFFER_SIZE {
+            return Err(format!(
+                "Vector clock has {} entries, at most {} are supported",
+                entries.len(),
+                MAX_BUFFER_SIZE
+            ));
+        }
+        let mut vc = VectorClock::new(entries.len() as i32);
+        vc.clock[..entries.len()].copy_from_slice(&entries);
+        Ok(vc)
+    }
+}
+
 impl fmt::Debug for VectorClock {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // Start with the struct name
@@ -71,6 +142,13 @@ impl fmt::Debug for VectorClock {
     }
 }
 
+// Active entries only, e.g. `[2, 0, 1]`
+impl fmt::Display for VectorClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", &self.clock[..self.size])
+    }
+}
+
 unsafe impl Equivalence for VectorClock {
     type Out = UserDatatype;
 
@@ -92,7 +170,7 @@ unsafe impl Equivalence for VectorClock {
     }
 }
 
-#[derive(Copy, Clone, Default, Debug)]
+#[derive(Copy, Clone, Default, Debug, Serialize, Deserialize)]
 pub(crate) struct MessagePayload {
     pub value: i32,
     pub message: i32,
@@ -100,6 +178,13 @@ pub(crate) struct MessagePayload {
     pub sender: Rank,
     pub receiver: Rank,
     pub time_stamp: VectorClock,
+    // Client operation an invocation belongs to, -1 when no client waits for a response
+    #[serde(default = "no_op_id")]
+    pub op_id: i32,
+}
+
+fn no_op_id() -> i32 {
+    -1
 }
 
 impl MessagePayload {
@@ -118,6 +203,41 @@ impl MessagePayload {
             sender: sender,
             receiver: receiver,
             time_stamp: ts,
+            op_id: -1,
+        }
+    }
+
+    pub fn with_op_id(mut self, op_id: i32) -> Self {
+        self.op_id = op_id;
+        self
+    }
+
+    // Human-readable name of the message code
+    pub fn kind(&self) -> &'static str {
+        match self.message {
+            0 => "EnqInvoke",
+            1 => "EnqReq",
+            2 => "EnqAck",
+            3 => "DeqInvoke",
+            4 => "DeqReq",
+            5 => "DeqAck",
+            6 => "Heartbeat",
+            7 => "JoinInvoke",
+            8 => "LeaveInvoke",
+            9 => "ViewChangeReq",
+            10 => "ViewChange",
+            11 => "ViewAck",
+            12 => "StateEntry",
+            13 => "StatePending",
+            14 => "ViewCommit",
+            15 => "StateMember",
+            16 => "SnapshotInvoke",
+            17 => "Marker",
+            18 => "SnapshotChunk",
+            19 => "SnapshotEnd",
+            20 => "AuditReport",
+            21 => "Digest",
+            _ => "Unknown",
         }
     }
 
@@ -129,6 +249,7 @@ impl MessagePayload {
             sender: -1,
             receiver: -1,
             time_stamp: VectorClock::default(),
+            op_id: -1,
         }
     }
 }
@@ -144,10 +265,11 @@ unsafe impl Equivalence for MessagePayload {
             offset_of!(MessagePayload, sender) as mpi::Address,
             offset_of!(MessagePayload, receiver) as mpi::Address,
             offset_of!(MessagePayload, time_stamp) as mpi::Address,
+            offset_of!(MessagePayload, op_id) as mpi::Address,
         ];
 
         UserDatatype::structured(
-            &[1, 1, 1, 1, 1, 1], // One block of each type
+            &[1, 1, 1, 1, 1, 1, 1], // One block of each type
             &displacements,
             &[
                 i32::equivalent_datatype(),                  // Datatype for message
@@ -156,7 +278,116 @@ unsafe impl Equivalence for MessagePayload {
                 Rank::equivalent_datatype(),                 // Datatype for sender
                 Rank::equivalent_datatype(),                 // Datatype for receiver
                 VectorClock::equivalent_datatype().as_ref(), // Datatype for time_stamp
+                i32::equivalent_datatype(),                  // Datatype for op_id
             ],
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vc(entries: &[i32]) -> VectorClock {
+        VectorClock::try_from(entries.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn compare_is_antisymmetric_across_sizes() {
+        let clocks = [
+            VectorClock::default(),
+            vc(&[0, 0]),
+            vc(&[1]),
+            vc(&[0, 1]),
+            vc(&[1, 0, 2]),
+            vc(&[1, 1]),
+        ];
+        for a in clocks.iter() {
+            for b in clocks.iter() {
+                assert_eq!(a.cmp(b), b.cmp(a).reverse(), "{:?} vs {:?}", a, b);
+                assert_eq!(a == b, a.cmp(b) == Ordering::Equal);
+            }
+        }
+    }
+
+    #[test]
+    fn default_clock_is_only_equal_to_zero_clocks() {
+        let zero = VectorClock::default();
+        assert_eq!(zero, vc(&[0, 0, 0]));
+        assert_ne!(zero, vc(&[0, 1]));
+        assert!(zero < vc(&[0, 1]));
+        assert!(vc(&[0, 1]) > zero);
+    }
+
+    #[test]
+    fn compare_is_lexicographic() {
+        assert!(vc(&[1, 5]) < vc(&[2, 0]));
+        assert!(vc(&[2, 0]) < vc(&[2, 0, 1]));
+        assert_eq!(vc(&[3, 4]).cmp(&vc(&[3, 4])), Ordering::Equal);
+        let mut sorted = [vc(&[2, 1]), vc(&[0, 3]), vc(&[2]), vc(&[1, 9])];
+        sorted.sort();
+        assert_eq!(
+            sorted.iter().map(|&c| Vec::from(c)).collect::<Vec<_>>(),
+            vec![vec![0, 3], vec![1, 9], vec![2], vec![2, 1]]
+        );
+    }
+
+    #[test]
+    fn causal_cmp_relates_clocks_element_wise() {
+        assert_eq!(vc(&[1, 2]).causal_cmp(&vc(&[1, 3])), CausalOrder::Before);
+        assert_eq!(vc(&[2, 3]).causal_cmp(&vc(&[1, 3])), CausalOrder::After);
+        assert_eq!(
+            vc(&[2, 0]).causal_cmp(&vc(&[0, 1])),
+            CausalOrder::Concurrent
+        );
+        assert_eq!(vc(&[4, 1]).causal_cmp(&vc(&[4, 1])), CausalOrder::Equal);
+        // Concurrent clocks are still ordered lexicographically
+        assert!(vc(&[0, 1]) < vc(&[2, 0]));
+    }
+
+    #[test]
+    fn causal_cmp_treats_missing_entries_as_zero() {
+        assert_eq!(vc(&[1]).causal_cmp(&vc(&[1, 0, 0])), CausalOrder::Equal);
+        assert_eq!(vc(&[1]).causal_cmp(&vc(&[1, 0, 2])), CausalOrder::Before);
+        assert_eq!(vc(&[1, 0, 2]).causal_cmp(&vc(&[1])), CausalOrder::After);
+        assert_eq!(vc(&[2]).causal_cmp(&vc(&[1, 1])), CausalOrder::Concurrent);
+        assert_eq!(
+            VectorClock::default().causal_cmp(&vc(&[0, 0])),
+            CausalOrder::Equal
+        );
+        assert_eq!(
+            VectorClock::default().causal_cmp(&vc(&[0, 1])),
+            CausalOrder::Before
+        );
+    }
+
+    #[test]
+    fn happens_before_is_a_strict_partial_order() {
+        let clocks = [
+            VectorClock::default(),
+            vc(&[1]),
+            vc(&[0, 1]),
+            vc(&[1, 1]),
+            vc(&[1, 0, 2]),
+            vc(&[2, 1, 2]),
+        ];
+        for a in clocks.iter() {
+            assert!(!a.happens_before(a));
+            for b in clocks.iter() {
+                if a.happens_before(b) {
+                    assert!(!b.happens_before(a), "{:?} vs {:?}", a, b);
+                    assert_eq!(b.causal_cmp(a), CausalOrder::After);
+                    // The total order extends it
+                    assert!(a < b);
+                    for c in clocks.iter() {
+                        if b.happens_before(c) {
+                            assert!(a.happens_before(c));
+                        }
+                    }
+                }
+            }
+        }
+        assert!(vc(&[1]).happens_before(&vc(&[2, 1, 2])));
+        assert!(!vc(&[0, 1]).happens_before(&vc(&[1, 0, 2])));
+    }
+}
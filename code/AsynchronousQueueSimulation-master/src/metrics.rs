@@ -0,0 +1,499 @@
+use mpi::Rank;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use tracing::{info, warn};
+
+use crate::admin::{AdminCommand, AdminRequest, OperationCounters};
+use crate::complexity::MessageComplexity;
+use crate::http;
+use crate::process_data::{Completion, OpOutcome, ProcessData};
+
+// Prometheus metrics, served as text on `GET /metrics` of the metrics port. Every series
+// carries a `rank` label so the ranks of one job can be scraped into a single dashboard.
+// The MPI thread owns the node state, so the server thread asks it to render them through
+// the admin channel.
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 5.0];
+
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    // Cumulative counts per bucket in `LATENCY_BUCKETS`
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+// Client connections, updated by the client and HTTP server threads
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    open: AtomicU64,
+    total: AtomicU64,
+}
+
+pub type SharedConnectionStats = Arc<ConnectionStats>;
+
+// Counts a connection as open for as long as it is alive
+pub struct ConnectionGuard(SharedConnectionStats);
+
+impl ConnectionGuard {
+    pub fn new(stats: &SharedConnectionStats) -> Self {
+        stats.open.fetch_add(1, Ordering::Relaxed);
+        stats.total.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(stats.clone())
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP async_queue_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE async_queue_{} {}", name, kind).unwrap();
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // Returned operations by (type, outcome)
+    operations: BTreeMap<(&'static str, &'static str), u64>,
+    latency: BTreeMap<&'static str, Histogram>,
+    pub connections: SharedConnectionStats,
+}
+
+impl Metrics {
+    pub fn on_completion(&mut self, completion: &Completion) {
+        let outcome = match completion.outcome {
+            OpOutcome::Rejected(_) => "rejected",
+            _ => "ok",
+        };
+        *self
+            .operations
+            .entry((completion.kind.name(), outcome))
+            .or_default() += 1;
+        if outcome == "ok" {
+            self.latency
+                .entry(completion.kind.name())
+                .or_default()
+                .observe(completion.latency.as_secs_f64());
+        }
+    }
+
+    pub fn render(
+        &self,
+        rank: Rank,
+        process_data: &ProcessData,
+        counters: &OperationCounters,
+        complexity: &MessageComplexity,
+    ) -> String {
+        let mut out = String::new();
+        header(
+            &mut out,
+            "operations_total",
+            "counter",
+            "Operations invoked at this process that returned, by type and outcome.",
+        );
+        for ((op, outcome), count) in self.operations.iter() {
+            writeln!(
+                out,
+                "async_queue_operations_total{{rank=\"{}\",op=\"{}\",outcome=\"{}\"}} {}",
+                rank, op, outcome, count
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "operation_latency_seconds",
+            "histogram",
+            "Time from executing an invocation until the operation returned.",
+        );
+        for (op, histogram) in self.latency.iter() {
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                writeln!(
+                    out,
+                    "async_queue_operation_latency_seconds_bucket{{rank=\"{}\",op=\"{}\",le=\"{}\"}} {}",
+                    rank, op, bound, count
+                )
+                .unwrap();
+            }
+            let labels = format!("rank=\"{}\",op=\"{}\"", rank, op);
+            writeln!(
+                out,
+                "async_queue_operation_latency_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "async_queue_operation_latency_seconds_sum{{{}}} {}",
+                labels, histogram.sum
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "async_queue_operation_latency_seconds_count{{{}}} {}",
+                labels, histogram.count
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "queue_depth",
+            "gauge",
+            "Elements in the local replica of the queue.",
+        );
+        writeln!(
+            out,
+            "async_queue_queue_depth{{rank=\"{}\"}} {}",
+            rank,
+            process_data.local_queue.len()
+        )
+        .unwrap();
+
+        // The FIFO queue has no per-process labels (those belong to the relaxed algorithm),
+        // so elements are counted by the process that enqueued them
+        header(
+            &mut out,
+            "queue_elements",
+            "gauge",
+            "Elements in the local replica by enqueuing process.",
+        );
+        let mut by_invoker: BTreeMap<Rank, u64> = BTreeMap::new();
+        for (_, invoker, _) in process_data.local_queue.iter() {
+            *by_invoker.entry(invoker).or_default() += 1;
+        }
+        for (invoker, count) in by_invoker {
+            writeln!(
+                out,
+                "async_queue_queue_elements{{rank=\"{}\",invoker=\"{}\"}} {}",
+                rank, invoker, count
+            )
+            .unwrap();
+        }
+
+        header(
+            &mut out,
+            "pending_dequeues",
+            "gauge",
+            "Confirmation lists of Dequeues that have not executed yet.",
+        );
+        writeln!(
+            out,
+            "async_queue_pending_dequeues{{rank=\"{}\"}} {}",
+            rank,
+            process_data
+                .pending_dequeues
+                .iter()
+                .filter(|list| !list.handled)
+                .count()
+        )
+        .unwrap();
+
+        for (name, help, by_kind) in [
+            (
+                "messages_received_total",
+                "Messages received, by kind.",
+                &counters.received,
+            ),
+            (
+                "messages_sent_total",
+                "Messages sent, by kind.",
+                &counters.sent,
+            ),
+        ] {
+            header(&mut out, name, "counter", help);
+            for (kind, count) in by_kind.iter() {
+                writeln!(
+                    out,
+                    "async_queue_{}{{rank=\"{}\",kind=\"{}\"}} {}",
+                    name, rank, kind, count
+                )
+                .unwrap();
+            }
+        }
+
+        header(
+            &mut out,
+            "frames_sent_total",
+            "counter",
+            "MPI sends, each carrying one or more batched messages.",
+        );
+        writeln!(
+            out,
+            "async_queue_frames_sent_total{{rank=\"{}\"}} {}",
+            rank, counters.frames_sent
+        )
+        .unwrap();
+
+        header(
+            &mut out,
+            "digest_divergences_total",
+            "counter",
+            "Dequeue hash chains that differed from another member's at the same point.",
+        );
+        writeln!(
+            out,
+            "async_queue_digest_divergences_total{{rank=\"{}\"}} {}",
+            rank, counters.digest_divergences
+        )
+        .unwrap();
+
+        header(
+            &mut out,
+            "audited_dequeues_total",
+            "counter",
+            "Dequeues every executing replica reported to the auditor.",
+        );
+        writeln!(
+            out,
+            "async_queue_audited_dequeues_total{{rank=\"{}\"}} {}",
+            rank, counters.audited_dequeues
+        )
+        .unwrap();
+
+        header(
+            &mut out,
+            "audit_divergences_total",
+            "counter",
+            "Reports to the auditor that removed a different value than the first report.",
+        );
+        writeln!(
+            out,
+            "async_queue_audit_divergences_total{{rank=\"{}\"}} {}",
+            rank, counters.audit_divergences
+        )
+        .unwrap();
+
+        let reports = complexity.report();
+        header(
+            &mut out,
+            "accounted_operations_total",
+            "counter",
+            "Operations this process has completed its part of, by type.",
+        );
+        for (op, report) in reports.iter() {
+            writeln!(
+                out,
+                "async_queue_accounted_operations_total{{rank=\"{}\",op=\"{}\"}} {}",
+                rank, op, report.operations
+            )
+            .unwrap();
+        }
+        header(
+            &mut out,
+            "operation_messages_total",
+            "counter",
+            "Messages sent and received on behalf of completed operations, by type and kind.",
+        );
+        for (op, report) in reports.iter() {
+            for (direction, by_kind) in [("sent", &report.sent), ("received", &report.received)] {
+                for (kind, count) in by_kind.iter() {
+                    writeln!(
+                        out,
+                        "async_queue_operation_messages_total{{rank=\"{}\",op=\"{}\",direction=\"{}\",kind=\"{}\"}} {}",
+                        rank, op, direction, kind, count
+                    )
+                    .unwrap();
+                }
+            }
+        }
+
+        header(
+            &mut out,
+            "client_connections",
+            "gauge",
+            "Open connections on the client and HTTP ports.",
+        );
+        writeln!(
+            out,
+            "async_queue_client_connections{{rank=\"{}\"}} {}",
+            rank,
+            self.connections.open.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        header(
+            &mut out,
+            "client_connections_total",
+            "counter",
+            "Connections accepted on the client and HTTP ports.",
+        );
+        writeln!(
+            out,
+            "async_queue_client_connections_total{{rank=\"{}\"}} {}",
+            rank,
+            self.connections.total.load(Ordering::Relaxed)
+        )
+        .unwrap();
+        out
+    }
+}
+
+fn handle_scrape(stream: TcpStream, admin_tx: mpsc::Sender<AdminRequest>) {
+    http::set_timeouts(&stream);
+    let request = match http::read_request(&stream) {
+        Ok(request) => request,
+        Err((status, error)) => {
+            http::write_response(&stream, status, "text/plain", &error.message);
+            return;
+        }
+    };
+    if request.method != "GET" || request.path.split('?').next() != Some("/metrics") {
+        http::write_response(&stream, 404, "text/plain", "Not found, try /metrics\n");
+        return;
+    }
+    let (reply_tx, reply_rx) = mpsc::channel();
+    let sent = admin_tx.send(AdminRequest {
+        command: AdminCommand::Metrics,
+        reply: reply_tx,
+    });
+    match sent.ok().and_then(|_| reply_rx.recv().ok()) {
+        Some(body) => http::write_response(&stream, 200, "text/plain; version=0.0.4", &body),
+        None => http::write_response(&stream, 503, "text/plain", "MPI thread has stopped\n"),
+    }
+}
+
+pub fn start_metrics_server(
+    port: u16,
+    rank: Rank,
+    admin_tx: mpsc::Sender<AdminRequest>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    info!(rank, port, "metrics server listening");
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let admin_tx = admin_tx.clone();
+                thread::spawn(move || handle_scrape(stream, admin_tx));
+            }
+            Err(e) => {
+                warn!(rank, "failed to accept metrics client: {}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::message_payload::{MessagePayload, VectorClock};
+    use crate::process_data::OpKind;
+    use std::time::Duration;
+
+    fn completion(kind: OpKind, outcome: OpOutcome, millis: u64) -> Completion {
+        Completion {
+            op_id: 0,
+            kind,
+            ts: VectorClock::default(),
+            outcome,
+            latency: Duration::from_millis(millis),
+        }
+    }
+
+    // Rank 3 of four holding one element, after four Enqueues and a Dequeue returned there
+    // and one Enqueue was rejected
+    fn rendered() -> String {
+        let mut metrics = Metrics::default();
+        for millis in [2, 30, 2000, 10_000] {
+            metrics.on_completion(&completion(OpKind::Enqueue, OpOutcome::Enqueued, millis));
+        }
+        metrics.on_completion(&completion(OpKind::Dequeue, OpOutcome::Dequeued(None), 1));
+        metrics.on_completion(&completion(
+            OpKind::Enqueue,
+            OpOutcome::Rejected("not a member of the current configuration"),
+            0,
+        ));
+        let mut process_data = ProcessData::new(3, 4, &Config::default());
+        let ts = VectorClock::try_from(vec![1, 0, 0, 0]).unwrap();
+        process_data.execute_locally(MessagePayload::new(1, 5, 0, 0, 3, ts));
+        let mut counters = OperationCounters::default();
+        counters.on_received(&MessagePayload::new(1, 5, 0, 0, 3, ts));
+        metrics.render(3, &process_data, &counters, &MessageComplexity::new(3))
+    }
+
+    // (name, labels, value) of every sample line
+    fn samples(text: &str) -> Vec<(String, String, f64)> {
+        text.lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| {
+                let (series, value) = line.rsplit_once(' ').unwrap();
+                let (name, labels) = series.split_once('{').unwrap();
+                let labels = labels.strip_suffix('}').unwrap();
+                (name.to_string(), labels.to_string(), value.parse().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_series_has_a_rank_label_and_a_type() {
+        let text = rendered();
+        let samples = samples(&text);
+        assert!(!samples.is_empty());
+        for (name, labels, _) in samples.iter() {
+            assert!(labels.starts_with("rank=\"3\""), "{}{{{}}}", name, labels);
+            let family = name
+                .trim_end_matches("_bucket")
+                .trim_end_matches("_sum")
+                .trim_end_matches("_count");
+            assert!(
+                text.contains(&format!("# TYPE {} ", family)),
+                "no TYPE for {}",
+                name
+            );
+        }
+        assert!(text.contains("async_queue_queue_depth{rank=\"3\"} 1\n"));
+        assert!(text.contains("async_queue_queue_elements{rank=\"3\",invoker=\"0\"} 1\n"));
+        assert!(text.contains(
+            "async_queue_operations_total{rank=\"3\",op=\"enqueue\",outcome=\"rejected\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn latency_buckets_are_cumulative_up_to_the_count() {
+        let samples = samples(&rendered());
+        let series = |suffix: &str| -> Vec<(String, f64)> {
+            samples
+                .iter()
+                .filter(|(name, labels, _)| {
+                    name == &format!("async_queue_operation_latency_seconds_{}", suffix)
+                        && labels.contains("op=\"enqueue\"")
+                })
+                .map(|(_, labels, value)| (labels.clone(), *value))
+                .collect()
+        };
+        let buckets = series("bucket");
+        assert_eq!(buckets.len(), LATENCY_BUCKETS.len() + 1);
+        let counts: Vec<f64> = buckets.iter().map(|(_, value)| *value).collect();
+        assert_eq!(
+            counts,
+            vec![0.0, 1.0, 1.0, 1.0, 1.0, 2.0, 2.0, 2.0, 2.0, 3.0, 4.0]
+        );
+        assert!(counts.windows(2).all(|pair| pair[0] <= pair[1]));
+        let (last_labels, inf) = buckets.last().unwrap();
+        assert!(last_labels.ends_with("le=\"+Inf\""));
+        // Rejected operations are not timed
+        let count = series("count");
+        assert_eq!(count, vec![("rank=\"3\",op=\"enqueue\"".to_string(), *inf)]);
+        assert!((series("sum")[0].1 - 12.032).abs() < 1e-9);
+    }
+}
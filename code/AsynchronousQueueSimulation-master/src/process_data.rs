@@ -1,53 +1,275 @@
 use mpi::Rank;
-use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
 
+use crate::config::Config;
+use crate::digest::ReplicaDigest;
+use crate::local_queue::LocalQueue;
+use crate::logging;
+use crate::membership::{Membership, ViewChange, COORDINATOR};
 use crate::message_payload::{MessagePayload, VectorClock};
+use crate::snapshot::Snapshot;
+use crate::timestamp_order::TimestampOrder;
+
+// How a process advances its own clock when it receives another process' timestamp. The
+// default keeps the original behaviour; `--clock-rule paper` selects the paper's rule.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ClockRule {
+    // Paper's updateTS: increment the local entry, then take the element-wise max
+    IncrementAndMerge,
+    // Only take the element-wise max; the local entry advances on invocations alone
+    #[default]
+    MaxOnly,
+}
+
+impl FromStr for ClockRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "paper" | "increment-and-merge" => Ok(ClockRule::IncrementAndMerge),
+            "max-only" => Ok(ClockRule::MaxOnly),
+            _ => Err(format!("Unknown clock rule: {}", s)),
+        }
+    }
+}
+
+// Experimental crash tolerance for the FIFO queue.
+//
+// `All` is the algorithm from the paper: an Enqueue returns after an EnqAck from every
+// process and a Dequeue executes once its confirmation list has a response from every
+// process. It is linearizable but a single crashed process blocks every later operation.
+//
+// `Majority` tolerates f < n/2 crashed processes. An Enqueue returns after EnqAcks from a
+// majority, and a confirmation list is complete once it holds responses from a majority that
+// includes every process the failure detector does not suspect. What it keeps:
+// - Liveness for correct processes as long as a majority is alive and crashed processes are
+//   eventually suspected.
+// - Every process that executes a Dequeue still removes the oldest element it holds with an
+//   earlier timestamp, in timestamp order of the confirmation lists.
+// - Elements of a crashed invoker are recovered: when a member starts suspecting a rank it
+//   re-broadcasts the EnqReqs of that rank's elements it still holds, so an element whose
+//   EnqReq reached only some replicas before the crash reaches every correct one. Replicas
+//   apply each Enqueue once, recognising repeats by the invoker's own clock entry.
+// What it gives up:
+// - Linearizability. A falsely suspected process, or an EnqReq still in flight from a crashed
+//   invoker, can let replicas execute the same Dequeue against different queue contents, and
+//   an element one replica already dequeued may still be recovered into the others.
+// The FIFO queue has no labeled elements (those belong to the relaxed algorithm), so the
+// recovered elements are the invoker's unlabeled ones.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum AckPolicy {
+    #[default]
+    All,
+    Majority,
+}
+
+impl FromStr for AckPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(AckPolicy::All),
+            "majority" => Ok(AckPolicy::Majority),
+            _ => Err(format!("Unknown ack policy: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OpOutcome {
+    Enqueued,
+    Dequeued(Option<i32>),
+    Rejected(&'static str),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OpKind {
+    Enqueue,
+    Dequeue,
+}
+
+impl OpKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OpKind::Enqueue => "enqueue",
+            OpKind::Dequeue => "dequeue",
+        }
+    }
+}
+
+// Result of an operation invoked at this process; `op_id` is -1 when no client waits for it
+#[derive(Clone, Debug)]
+pub struct Completion {
+    pub op_id: i32,
+    pub kind: OpKind,
+    // Timestamp the operation was issued with, the default when it was rejected
+    pub ts: VectorClock,
+    pub outcome: OpOutcome,
+    // From the invocation being executed here until the operation returned
+    pub latency: Duration,
+}
+
+// An Enqueue or Dequeue invoked at this process that has not returned yet
+#[derive(Copy, Clone, Debug)]
+struct RunningOp {
+    op_id: i32,
+    ts: VectorClock,
+    started: Instant,
+}
 
 pub struct ProcessData {
     rank: Rank,
     world_size: i32,
     pub timestamp: VectorClock,
     pub enq_count: i32,
+    pub enq_pending: bool,
+    // Timestamp of the Enqueue waiting for acks; acks naming another Enqueue are stale
+    pub enq_ts: VectorClock,
+    // Members whose ack for that Enqueue has been counted
+    pub enq_acked: Vec<bool>,
+    pub deq_pending: bool,
     pub pending_dequeues: Vec<ConfirmationList>,
     pub message_buffer: Vec<MessagePayload>,
     pub message_history: Vec<MessagePayload>,
-    pub local_queue: VecDeque<(i32, Rank, VectorClock)>,
+    pub local_queue: LocalQueue,
     pub locked: bool,
+    pub clock_rule: ClockRule,
+    pub ts_order: TimestampOrder,
+    pub ack_policy: AckPolicy,
+    pub suspected: Vec<Rank>,
+    pub membership: Membership,
+    pub applied_lsn: u64,
+    // Per invoker, its own clock entry in the last EnqReq applied here
+    pub applied_enqueues: Vec<i32>,
+    // (timestamp, invoker, removed value) of every Dequeue executed since last drained
+    pub executed_dequeues: Vec<(VectorClock, Rank, i32)>,
+    pub digest: ReplicaDigest,
+    // Operations invoked at this process that have returned
+    pub completed_enqueues: u64,
+    pub completed_dequeues: u64,
+    // Operations running at this process: the Enqueue and the Dequeues by timestamp
+    running_enqueue: Option<RunningOp>,
+    running_dequeues: BTreeMap<VectorClock, RunningOp>,
+    // Operations that returned since last drained by main
+    pub completions: Vec<Completion>,
 }
 
 impl ProcessData {
-    pub fn new(rank: Rank, size: i32) -> Self {
+    pub fn new(rank: Rank, size: i32, config: &Config) -> Self {
         ProcessData {
             rank,
             world_size: size,
             timestamp: VectorClock::new(size),
             enq_count: 0,
+            enq_pending: false,
+            enq_ts: VectorClock::default(),
+            enq_acked: vec![false; size as usize],
+            deq_pending: false,
             pending_dequeues: Vec::new(),
             message_buffer: vec![MessagePayload::with_msg(-1); size as usize],
             message_history: Vec::new(),
-            local_queue: VecDeque::new(),
+            local_queue: LocalQueue::new(config.ts_order),
             locked: false,
+            clock_rule: config.clock_rule,
+            ts_order: config.ts_order,
+            ack_policy: config.ack_policy,
+            suspected: Vec::new(),
+            membership: Membership::new(size, config.initial_members.unwrap_or(size)),
+            applied_lsn: 0,
+            applied_enqueues: vec![0; size as usize],
+            executed_dequeues: Vec::new(),
+            digest: ReplicaDigest::new(config.digest_every),
+            completed_enqueues: 0,
+            completed_dequeues: 0,
+            running_enqueue: None,
+            running_dequeues: BTreeMap::new(),
+            completions: Vec::new(),
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            rank: self.rank,
+            world_size: self.world_size,
+            timestamp: self.timestamp,
+            enq_count: self.enq_count,
+            enq_pending: self.enq_pending,
+            enq_ts: self.enq_ts,
+            enq_acked: self.enq_acked.clone(),
+            applied_enqueues: self.applied_enqueues.clone(),
+            deq_pending: self.deq_pending,
+            locked: self.locked,
+            local_queue: self.local_queue.iter().collect(),
+            pending_dequeues: self.pending_dequeues.clone(),
+            members: self.membership.members.clone(),
+            applied_lsn: self.applied_lsn,
+            digest: self.digest.clone(),
         }
     }
 
+    pub fn restore(&mut self, snapshot: Snapshot) -> Result<(), String> {
+        if snapshot.rank != self.rank || snapshot.world_size != self.world_size {
+            return Err(format!(
+                "Snapshot of process {} in a world of {} cannot restore process {} in a world of {}",
+                snapshot.rank, snapshot.world_size, self.rank, self.world_size
+            ));
+        }
+        self.timestamp = snapshot.timestamp;
+        logging::record_clock(&self.timestamp);
+        self.enq_count = snapshot.enq_count;
+        self.enq_pending = snapshot.enq_pending;
+        self.enq_ts = snapshot.enq_ts;
+        self.enq_acked = snapshot.enq_acked;
+        self.deq_pending = snapshot.deq_pending;
+        self.locked = snapshot.locked;
+        self.local_queue = LocalQueue::new(self.ts_order);
+        self.digest = ReplicaDigest {
+            checkpoint_every: self.digest.checkpoint_every,
+            ..snapshot.digest
+        };
+        self.digest.queue = 0;
+        for element in snapshot.local_queue {
+            self.ordered_insert(element);
+        }
+        self.pending_dequeues = snapshot.pending_dequeues;
+        self.membership.members = snapshot.members;
+        self.applied_lsn = snapshot.applied_lsn;
+        self.applied_enqueues = snapshot.applied_enqueues;
+        Ok(())
+    }
+
     pub fn increment_ts(&mut self) {
         self.timestamp.clock[self.rank as usize] += 1;
+        logging::record_clock(&self.timestamp);
     }
 
     pub fn update_ts(&mut self, v_j: &VectorClock) {
+        if self.clock_rule == ClockRule::IncrementAndMerge {
+            self.increment_ts();
+        }
         let max_index = self.timestamp.size;
         for i in 0..max_index {
             if v_j.clock[i] > self.timestamp.clock[i] {
                 self.timestamp.clock[i] = v_j.clock[i];
             }
         }
+        logging::record_clock(&self.timestamp);
     }
 
-    fn find_insert_position(&self, value: &VectorClock) -> usize {
-        self.local_queue
-            .iter()
-            .position(|x| value < &x.2)
-            .unwrap_or(self.local_queue.len())
+    // Responses from processes outside the current configuration are never awaited
+    fn new_confirmation_list(&self, deq_ts: VectorClock, deq_invoker: Rank) -> ConfirmationList {
+        let mut cl = ConfirmationList::new(self.world_size, deq_ts, deq_invoker);
+        for r in 0..self.world_size {
+            if !self.membership.is_member(r) {
+                cl.response_buffer[r as usize] = 1;
+            }
+        }
+        cl
     }
 
     fn contains_timestamp(&self, target: &VectorClock) -> bool {
@@ -57,34 +279,23 @@ impl ProcessData {
     }
 
     pub fn ordered_insert(&mut self, value: (i32, Rank, VectorClock)) {
-        let position = self.find_insert_position(&value.2);
-        self.local_queue.insert(position, value);
+        self.digest.on_insert(value.0, value.1, &value.2);
+        self.local_queue.insert(value);
     }
 
-    pub fn dequeue(&mut self, ts: VectorClock) -> Option<(i32, Rank, VectorClock)> {
-        let mut oldest_index = None;
-        let mut oldest_timestamp: Option<&VectorClock> = None;
-
-        for (index, element) in self.local_queue.iter().enumerate() {
-            if &element.2 < &ts {
-                if oldest_timestamp.is_none() || &element.2 < oldest_timestamp.unwrap() {
-                    oldest_index = Some(index);
-                    oldest_timestamp = Some(&element.2);
-                }
-            }
-        }
-
-        if let Some(index) = oldest_index {
-            self.local_queue.remove(index)
-        } else {
-            None
+    pub fn dequeue(&mut self, ts: VectorClock, invoker: Rank) -> Option<(i32, Rank, VectorClock)> {
+        let removed = self.local_queue.dequeue_before(&ts, invoker);
+        if let Some((val, inv, elem_ts)) = &removed {
+            self.digest.on_remove(*val, *inv, elem_ts);
         }
+        removed
     }
 
     pub fn insert_by_ts(&mut self, new_cl: ConfirmationList) {
+        let order = self.ts_order;
         let pos = self
             .pending_dequeues
-            .binary_search_by(|cl| cl.ts.compare(&new_cl.ts))
+            .binary_search_by(|cl| order.compare(&cl.ts, cl.invoker, &new_cl.ts, new_cl.invoker))
             .unwrap_or_else(|e| e);
         self.pending_dequeues.insert(pos, new_cl);
     }
@@ -100,6 +311,296 @@ impl ProcessData {
         }
     }
 
+    fn quorum(&self) -> i32 {
+        match self.ack_policy {
+            AckPolicy::All => self.membership.count(),
+            AckPolicy::Majority => self.membership.count() / 2 + 1,
+        }
+    }
+
+    // Replicas expected to execute each Dequeue. Under `Majority` suspected members may
+    // never get to it, while the rest all wait for their own response.
+    pub fn executing_replicas(&self) -> i32 {
+        match self.ack_policy {
+            AckPolicy::All => self.membership.count(),
+            AckPolicy::Majority => {
+                let unsuspected = self
+                    .membership
+                    .ranks()
+                    .iter()
+                    .filter(|r| !self.suspected.contains(r))
+                    .count() as i32;
+                unsuspected.max(self.quorum())
+            }
+        }
+    }
+
+    fn confirmation_complete(&self, cl: &ConfirmationList) -> bool {
+        match self.ack_policy {
+            AckPolicy::All => cl.is_full(),
+            AckPolicy::Majority => {
+                let members = self.membership.ranks();
+                let responses = members
+                    .iter()
+                    .filter(|&&r| cl.response_buffer[r as usize] != 0)
+                    .count();
+                let unsuspected_responded = members
+                    .iter()
+                    .filter(|r| !self.suspected.contains(r))
+                    .all(|&r| cl.response_buffer[r as usize] != 0);
+                responses as i32 >= self.quorum() && unsuspected_responded
+            }
+        }
+    }
+
+    // EnqReqs of one invoker arrive in its order, so one whose invoker entry is not newer
+    // than the last applied was applied already
+    fn applied_enqueue(&self, enq_req: &MessagePayload) -> bool {
+        let invoker = enq_req.invoker as usize;
+        enq_req.time_stamp.clock[invoker] <= self.applied_enqueues[invoker]
+    }
+
+    // Executes, in timestamp order, every pending Dequeue whose confirmation list is complete.
+    // Each list executes with its own timestamp and invoker. The original loop dequeued with
+    // the timestamp of the DeqAck that was just received, which under `All` differs whenever
+    // that ack completes an earlier list too.
+    fn execute_ready_dequeues(&mut self) {
+        let mut i = 0;
+        while i < self.pending_dequeues.len() {
+            if self.confirmation_complete(&self.pending_dequeues[i])
+                && !self.pending_dequeues[i].handled
+            {
+                let ts = self.pending_dequeues[i].ts;
+                let invoker = self.pending_dequeues[i].invoker;
+                let removed = self.dequeue(ts, invoker).map(|(val, _, _)| val);
+                let ret = removed.unwrap_or(-1);
+                self.pending_dequeues[i].handled = true;
+                self.executed_dequeues.push((ts, invoker, ret));
+                self.digest.on_dequeue(&ts, ret);
+                if self.rank == invoker {
+                    self.deq_pending = false;
+                    self.completed_dequeues += 1;
+                    info!(value = ret, "dequeued");
+                    if let Some(op) = self.running_dequeues.remove(&ts) {
+                        self.completions.push(Completion {
+                            op_id: op.op_id,
+                            kind: OpKind::Dequeue,
+                            ts,
+                            outcome: OpOutcome::Dequeued(removed),
+                            latency: op.started.elapsed(),
+                        });
+                    }
+                }
+            }
+            i += 1;
+        }
+    }
+
+    fn complete_enqueue(&mut self) {
+        info!(local_queue = ?self.local_queue, "done enqueueing");
+        self.enq_pending = false;
+        self.completed_enqueues += 1;
+        self.locked = false;
+        if let Some(op) = self.running_enqueue.take() {
+            self.completions.push(Completion {
+                op_id: op.op_id,
+                kind: OpKind::Enqueue,
+                ts: op.ts,
+                outcome: OpOutcome::Enqueued,
+                latency: op.started.elapsed(),
+            });
+        }
+    }
+
+    // Called when the failure detector's verdict changes; under a majority policy this may
+    // complete operations that were only waiting on the newly suspected ranks, and returns
+    // the EnqReqs that recover the elements of newly suspected invokers
+    pub fn set_suspected(&mut self, suspected: &[Rank]) -> Vec<MessagePayload> {
+        let mut messages_to_send = Vec::new();
+        if self.ack_policy == AckPolicy::Majority {
+            let newly_suspected: Vec<Rank> = suspected
+                .iter()
+                .copied()
+                .filter(|rank| !self.suspected.contains(rank))
+                .collect();
+            for (value, invoker, ts) in self.local_queue.iter() {
+                if !newly_suspected.contains(&invoker) {
+                    continue;
+                }
+                for recv_rank in self.membership.ranks() {
+                    if recv_rank != self.rank && !suspected.contains(&recv_rank) {
+                        messages_to_send.push(MessagePayload::new(
+                            1, value, invoker, self.rank, recv_rank, ts,
+                        ));
+                    }
+                }
+            }
+        }
+        self.suspected = suspected.to_vec();
+        if self.ack_policy == AckPolicy::Majority {
+            if self.enq_pending && self.enq_count >= self.quorum() {
+                self.complete_enqueue();
+            }
+            self.execute_ready_dequeues();
+        }
+        messages_to_send
+    }
+
+    // Describes the operations that cannot complete while any of `suspected` stays silent
+    pub fn stalled_operations(&self, suspected: &[Rank]) -> Vec<String> {
+        let mut stalled = Vec::new();
+        if suspected.is_empty() {
+            return stalled;
+        }
+        if self.enq_pending {
+            stalled.push(format!(
+                "Enqueue at process {} has {} of {} acknowledgements, suspected ranks {:?}",
+                self.rank,
+                self.enq_count,
+                self.quorum(),
+                suspected
+            ));
+        }
+        for cl in self.pending_dequeues.iter().filter(|cl| !cl.handled) {
+            let missing: Vec<Rank> = suspected
+                .iter()
+                .copied()
+                .filter(|&r| cl.response_buffer[r as usize] == 0)
+                .collect();
+            if !missing.is_empty() {
+                stalled.push(format!(
+                    "Dequeue {:?} invoked by process {} is waiting on suspected ranks {:?}",
+                    cl.ts, cl.invoker, missing
+                ));
+            }
+        }
+        stalled
+    }
+
+    // A member has nothing in flight once its own operations returned and every Dequeue it
+    // knows about has been executed
+    fn quiescent(&self) -> bool {
+        !self.enq_pending && !self.deq_pending && self.pending_dequeues.iter().all(|cl| cl.handled)
+    }
+
+    fn start_view_change(&mut self, change: ViewChange) -> Vec<MessagePayload> {
+        self.increment_ts();
+        self.membership.current_change = Some(change);
+        self.membership
+            .ranks()
+            .into_iter()
+            .map(|recv_rank| {
+                MessagePayload::new(
+                    10,
+                    change.value(),
+                    change.subject,
+                    self.rank,
+                    recv_rank,
+                    self.timestamp,
+                )
+            })
+            .collect()
+    }
+
+    // Acknowledges an announced ViewChange as soon as this member has drained
+    fn view_ack_if_quiescent(&mut self) -> Vec<MessagePayload> {
+        let mut messages_to_send = Vec::new();
+        if self.membership.reconfiguring && !self.membership.ack_sent && self.quiescent() {
+            self.membership.ack_sent = true;
+            messages_to_send.push(MessagePayload::new(
+                11,
+                0,
+                self.rank,
+                self.rank,
+                COORDINATOR,
+                self.timestamp,
+            ));
+        }
+        messages_to_send
+    }
+
+    // Coordinator side: every member drained, so hand the replica to a joining process and
+    // commit the new configuration everywhere
+    fn commit_view_change(&mut self, change: ViewChange) -> Vec<MessagePayload> {
+        let mut messages_to_send = Vec::new();
+        if change.joining {
+            for member in 0..self.world_size {
+                messages_to_send.push(MessagePayload::new(
+                    15,
+                    self.membership.is_member(member) as i32,
+                    member,
+                    self.rank,
+                    change.subject,
+                    self.timestamp,
+                ));
+            }
+            for (val, invoker, ts) in self.local_queue.iter() {
+                messages_to_send.push(MessagePayload::new(
+                    12,
+                    val,
+                    invoker,
+                    self.rank,
+                    change.subject,
+                    ts,
+                ));
+            }
+            for cl in self.pending_dequeues.iter() {
+                messages_to_send.push(MessagePayload::new(
+                    13,
+                    cl.handled as i32,
+                    cl.invoker,
+                    self.rank,
+                    change.subject,
+                    cl.ts,
+                ));
+            }
+        }
+        let mut recipients = self.membership.ranks();
+        if change.joining {
+            recipients.push(change.subject);
+        }
+        for recv_rank in recipients {
+            messages_to_send.push(MessagePayload::new(
+                14,
+                change.value(),
+                change.subject,
+                self.rank,
+                recv_rank,
+                self.timestamp,
+            ));
+        }
+        messages_to_send
+    }
+
+    fn apply_view_change(&mut self, change: ViewChange, ts: &VectorClock) -> Vec<MessagePayload> {
+        let mut messages_to_send = Vec::new();
+        self.membership.apply(change);
+        if change.subject == self.rank {
+            if change.joining {
+                self.update_ts(ts);
+                info!(local_queue = ?self.local_queue, "joined");
+            } else {
+                self.local_queue = LocalQueue::new(self.ts_order);
+                self.pending_dequeues.clear();
+                self.digest.queue = 0;
+                info!("left the cluster");
+            }
+        }
+        info!(members = ?self.membership.ranks(), "installed configuration");
+        // Invocations that arrived during the change are re-issued to this process. Nothing is
+        // in flight, so the lock taken when they were first sent can be released.
+        if !self.membership.deferred.is_empty() {
+            self.locked = false;
+            messages_to_send.append(&mut self.membership.deferred);
+        }
+        if self.rank == COORDINATOR {
+            if let Some(next) = self.membership.queued_changes.pop_front() {
+                messages_to_send.append(&mut self.start_view_change(next));
+            }
+        }
+        messages_to_send
+    }
+
     /*
     // For use in the relaxed version of this algorithm
     pub fn update_unsafes(&mut self, start_index: usize) {
@@ -113,12 +614,45 @@ impl ProcessData {
     pub fn execute_locally(&mut self, message_payload: MessagePayload) -> Vec<MessagePayload> {
         let mut messages_to_send: Vec<MessagePayload> = Vec::new();
 
-        match message_payload.message {
+        let is_invocation = matches!(message_payload.message, 0 | 3 | 7 | 8);
+        if is_invocation && self.membership.reconfiguring {
+            // Hold new operations until the configuration change commits
+            self.membership.deferred.push(message_payload);
+            return messages_to_send;
+        }
+        if matches!(message_payload.message, 0 | 3) && !self.membership.is_member(self.rank) {
+            warn!(
+                kind = message_payload.kind(),
+                "not a member of the current configuration, rejecting invocation"
+            );
+            self.completions.push(Completion {
+                op_id: message_payload.op_id,
+                kind: if message_payload.message == 0 {
+                    OpKind::Enqueue
+                } else {
+                    OpKind::Dequeue
+                },
+                ts: VectorClock::default(),
+                outcome: OpOutcome::Rejected("not a member of the current configuration"),
+                latency: Duration::ZERO,
+            });
+            return messages_to_send;
+        }
+
+        let mut messages_to_send = match message_payload.message {
             0 => {
                 // Enq invoke
                 self.enq_count = 0;
+                self.enq_acked = vec![false; self.world_size as usize];
+                self.enq_pending = true;
                 self.increment_ts();
-                for recv_rank in 0..self.world_size {
+                self.enq_ts = self.timestamp;
+                self.running_enqueue = Some(RunningOp {
+                    op_id: message_payload.op_id,
+                    ts: self.timestamp,
+                    started: Instant::now(),
+                });
+                for recv_rank in self.membership.ranks() {
                     let message_to_send: MessagePayload = MessagePayload::new(
                         1,
                         message_payload.value,
@@ -131,47 +665,90 @@ impl ProcessData {
                 }
                 messages_to_send
             }
+            1 if self.applied_enqueue(&message_payload) => {
+                // A repeat, from a member recovering a suspected invoker's elements or from an
+                // invoker re-sending after a restart. It is acked again in case the first ack
+                // was lost; the invoker counts one ack per member.
+                self.update_ts(&message_payload.time_stamp);
+                messages_to_send.push(MessagePayload::new(
+                    2,
+                    message_payload.value,
+                    message_payload.invoker,
+                    self.rank,
+                    message_payload.invoker,
+                    message_payload.time_stamp,
+                ));
+                messages_to_send
+            }
             1 => {
                 // Receive EnqReq
                 self.update_ts(&message_payload.time_stamp);
+                let invoker = message_payload.invoker as usize;
+                self.applied_enqueues[invoker] = message_payload.time_stamp.clock[invoker];
+                self.digest.on_enqueue(
+                    message_payload.value,
+                    message_payload.invoker,
+                    &message_payload.time_stamp,
+                );
                 self.ordered_insert((
                     message_payload.value,
                     message_payload.invoker,
                     message_payload.time_stamp,
                 ));
                 for confirmation_list in self.pending_dequeues.iter_mut() {
-                    if confirmation_list.ts < message_payload.time_stamp {
+                    if self.ts_order.compare(
+                        &confirmation_list.ts,
+                        confirmation_list.invoker,
+                        &message_payload.time_stamp,
+                        message_payload.invoker,
+                    ) == Ordering::Less
+                    {
                         confirmation_list.response_buffer[message_payload.invoker as usize] = 1;
                     }
                 }
+                // The ack echoes the EnqReq's timestamp, as a DeqAck does, so it names the
+                // Enqueue it belongs to
                 let message_to_send: MessagePayload = MessagePayload::new(
                     2,
                     message_payload.value,
                     message_payload.invoker,
                     self.rank,
                     message_payload.invoker,
-                    self.timestamp,
+                    message_payload.time_stamp,
                 );
                 messages_to_send.push(message_to_send);
                 messages_to_send
             }
+            2 if !self.enq_pending
+                || message_payload.time_stamp != self.enq_ts
+                || self.enq_acked[message_payload.sender as usize] =>
+            {
+                // Under `Majority` acks of an Enqueue that already returned may still arrive,
+                // and a repeated EnqReq is acked again; neither counts
+                messages_to_send
+            }
             2 => {
                 // Receive EnqAck
+                self.enq_acked[message_payload.sender as usize] = true;
                 self.enq_count += 1;
-                if self.enq_count == self.world_size {
-                    println!(
-                        "Process{} done enqueueing! Current local queue: {:?}",
-                        self.rank, self.local_queue
-                    ); // TODO make actual return
-                       // system
-                    self.locked = false;
+                if self.enq_count == self.quorum() {
+                    self.complete_enqueue();
                 }
                 messages_to_send
             }
             3 => {
                 // Deq invoke
+                self.deq_pending = true;
                 self.increment_ts();
-                for recv_rank in 0..self.world_size {
+                self.running_dequeues.insert(
+                    self.timestamp,
+                    RunningOp {
+                        op_id: message_payload.op_id,
+                        ts: self.timestamp,
+                        started: Instant::now(),
+                    },
+                );
+                for recv_rank in self.membership.ranks() {
                     let message_to_send: MessagePayload =
                         MessagePayload::new(4, 0, self.rank, self.rank, recv_rank, self.timestamp);
                     messages_to_send.push(message_to_send);
@@ -182,13 +759,12 @@ impl ProcessData {
                 // Receive DeqReq
                 self.update_ts(&message_payload.time_stamp);
                 if !self.contains_timestamp(&message_payload.time_stamp) {
-                    self.insert_by_ts(ConfirmationList::new(
-                        self.world_size,
+                    self.insert_by_ts(self.new_confirmation_list(
                         message_payload.time_stamp,
                         message_payload.invoker,
                     ));
                 }
-                for recv_rank in 0..self.world_size {
+                for recv_rank in self.membership.ranks() {
                     let message_to_send: MessagePayload = MessagePayload::new(
                         5,
                         0,
@@ -204,8 +780,7 @@ impl ProcessData {
             5 => {
                 // Receive DeqAck
                 if !self.contains_timestamp(&message_payload.time_stamp) {
-                    self.insert_by_ts(ConfirmationList::new(
-                        self.world_size,
+                    self.insert_by_ts(self.new_confirmation_list(
                         message_payload.time_stamp,
                         message_payload.invoker,
                     ));
@@ -217,35 +792,95 @@ impl ProcessData {
                         break;
                     }
                 }
-                let mut i = 0;
-                while i < self.pending_dequeues.len() {
-                    if self.pending_dequeues[i].is_full() && !self.pending_dequeues[i].handled {
-                        let ret: i32;
-
-                        match self.dequeue(message_payload.time_stamp) {
-                            Some((val, _, _)) => {
-                                ret = val;
-                            }
-                            _ => {
-                                ret = -1;
-                            }
-                        }
-                        self.pending_dequeues[i].handled = true;
-                        if self.rank == message_payload.invoker {
-                            println!("Process{} dequeued {}", self.rank, ret);
-                        }
+                self.execute_ready_dequeues();
+                self.locked = false;
+                messages_to_send
+            }
+            7 | 8 => {
+                // Join invoke (7) at a spare process, Leave invoke (8) at a member
+                let change = ViewChange {
+                    joining: message_payload.message == 7,
+                    subject: self.rank,
+                };
+                messages_to_send.push(MessagePayload::new(
+                    9,
+                    change.value(),
+                    self.rank,
+                    self.rank,
+                    COORDINATOR,
+                    self.timestamp,
+                ));
+                messages_to_send
+            }
+            9 => {
+                // Receive ViewChangeReq (coordinator)
+                let change = ViewChange::from_payload(&message_payload);
+                if change.joining == self.membership.is_member(change.subject)
+                    || (!change.joining && change.subject == COORDINATOR)
+                {
+                    warn!(?change, "rejected view change");
+                } else if self.membership.current_change.is_some() {
+                    self.membership.queued_changes.push_back(change);
+                } else {
+                    messages_to_send = self.start_view_change(change);
+                }
+                messages_to_send
+            }
+            10 => {
+                // Receive ViewChange
+                self.update_ts(&message_payload.time_stamp);
+                self.membership.reconfiguring = true;
+                messages_to_send
+            }
+            11 => {
+                // Receive ViewAck (coordinator)
+                self.membership.view_acks += 1;
+                if self.membership.view_acks == self.membership.count() {
+                    if let Some(change) = self.membership.current_change {
+                        messages_to_send = self.commit_view_change(change);
                     }
-                    i += 1;
                 }
-                self.locked = false;
+                messages_to_send
+            }
+            12 => {
+                // Receive StateEntry (joining process): an element of the coordinator's queue
+                let invoker = message_payload.invoker as usize;
+                self.applied_enqueues[invoker] =
+                    self.applied_enqueues[invoker].max(message_payload.time_stamp.clock[invoker]);
+                self.ordered_insert((
+                    message_payload.value,
+                    message_payload.invoker,
+                    message_payload.time_stamp,
+                ));
+                messages_to_send
+            }
+            13 => {
+                // Receive StatePending (joining process): a confirmation list of the coordinator
+                let mut cl =
+                    self.new_confirmation_list(message_payload.time_stamp, message_payload.invoker);
+                cl.handled = message_payload.value == 1;
+                self.insert_by_ts(cl);
+                messages_to_send
+            }
+            14 => {
+                // Receive ViewCommit
+                let change = ViewChange::from_payload(&message_payload);
+                self.apply_view_change(change, &message_payload.time_stamp)
+            }
+            15 => {
+                // Receive StateMember (joining process): the coordinator's view of one rank
+                self.membership.members[message_payload.invoker as usize] =
+                    message_payload.value == 1;
                 messages_to_send
             }
             _ => messages_to_send,
-        }
+        };
+        messages_to_send.append(&mut self.view_ack_if_quiescent());
+        messages_to_send
     }
 }
 
-#[derive(Clone, Default, Debug)]
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct ConfirmationList {
     pub response_buffer: Vec<i32>,
     pub ts: VectorClock,
@@ -267,3 +902,411 @@ impl ConfirmationList {
         self.response_buffer.iter().all(|&x| x == 1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timestamp_order::OrderKey;
+    use std::collections::{BTreeSet, VecDeque};
+
+    // Deterministic pseudo-random choices, so every interleaving can be replayed
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((self.0 >> 33) as usize) % n
+        }
+    }
+
+    // Runs `size` processes that each invoke `ops` alternating Enqueues and Dequeues, one at
+    // a time, while messages are delivered in a random order that is FIFO per channel.
+    // Returns the order key of every Enqueue and Dequeue issued.
+    fn run(config: &Config, size: i32, ops: usize, seed: u64) -> Vec<OrderKey> {
+        let mut rng = Lcg(seed);
+        let mut nodes: Vec<ProcessData> = (0..size)
+            .map(|rank| ProcessData::new(rank, size, config))
+            .collect();
+        let mut channels: BTreeMap<(Rank, Rank), VecDeque<MessagePayload>> = BTreeMap::new();
+        let mut invoked = vec![0; size as usize];
+        let mut keys = Vec::new();
+        loop {
+            let idle: Vec<Rank> = (0..size)
+                .filter(|&r| {
+                    let node = &nodes[r as usize];
+                    invoked[r as usize] < ops && !node.enq_pending && !node.deq_pending
+                })
+                .collect();
+            let busy: Vec<(Rank, Rank)> = channels
+                .iter()
+                .filter(|(_, queue)| !queue.is_empty())
+                .map(|(&channel, _)| channel)
+                .collect();
+            if idle.is_empty() && busy.is_empty() {
+                return keys;
+            }
+            let (rank, message) = if !idle.is_empty() && (busy.is_empty() || rng.below(2) == 0) {
+                let rank = idle[rng.below(idle.len())];
+                let code = if invoked[rank as usize].is_multiple_of(2) {
+                    0
+                } else {
+                    3
+                };
+                invoked[rank as usize] += 1;
+                let message =
+                    MessagePayload::new(code, 7, rank, rank, rank, VectorClock::default());
+                (rank, message)
+            } else {
+                let channel = busy[rng.below(busy.len())];
+                let message = channels.get_mut(&channel).unwrap().pop_front().unwrap();
+                (channel.1, message)
+            };
+            for sent in nodes[rank as usize].execute_locally(message) {
+                if matches!(sent.message, 1 | 4) && sent.receiver == sent.invoker {
+                    keys.push(config.ts_order.key(&sent.time_stamp, sent.invoker));
+                }
+                channels
+                    .entry((sent.sender, sent.receiver))
+                    .or_default()
+                    .push_back(sent);
+            }
+        }
+    }
+
+    fn assert_unique(clock_rule: ClockRule, ts_order: TimestampOrder) {
+        let config = Config {
+            clock_rule,
+            ts_order,
+            ..Config::default()
+        };
+        for seed in 0..50 {
+            for size in 2..=4 {
+                let keys = run(&config, size, 6, seed);
+                assert_eq!(keys.len(), size as usize * 6);
+                // Unique timestamps, not only unique (timestamp, invoker) keys
+                let unique: BTreeSet<VectorClock> = keys.iter().map(|key| key.2).collect();
+                assert_eq!(
+                    unique.len(),
+                    keys.len(),
+                    "duplicate timestamps under {:?}/{:?} with seed {}: {:?}",
+                    clock_rule,
+                    ts_order,
+                    seed,
+                    keys
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn concurrent_invocations_get_unique_timestamps_with_paper_rule() {
+        assert_unique(ClockRule::IncrementAndMerge, TimestampOrder::Lexicographic);
+        assert_unique(ClockRule::IncrementAndMerge, TimestampOrder::SumThenRank);
+    }
+
+    #[test]
+    fn concurrent_invocations_get_unique_timestamps_with_max_only_rule() {
+        assert_unique(ClockRule::MaxOnly, TimestampOrder::Lexicographic);
+        assert_unique(ClockRule::MaxOnly, TimestampOrder::SumThenRank);
+    }
+
+    fn invoke(node: &mut ProcessData, code: i32, value: i32, rank: Rank) -> Vec<MessagePayload> {
+        node.execute_locally(MessagePayload::new(
+            code,
+            value,
+            rank,
+            rank,
+            rank,
+            VectorClock::default(),
+        ))
+    }
+
+    #[test]
+    fn majority_ignores_acks_of_an_earlier_enqueue() {
+        let config = Config {
+            ack_policy: AckPolicy::Majority,
+            ..Config::default()
+        };
+        let mut invoker = ProcessData::new(0, 3, &config);
+        let mut peers: Vec<ProcessData> = (1..3)
+            .map(|rank| ProcessData::new(rank, 3, &config))
+            .collect();
+        let first = invoke(&mut invoker, 0, 1, 0);
+        // Acks from ranks 0 and 1 complete the first Enqueue; rank 2's is late
+        let ack_0 = invoker.execute_locally(first[0]);
+        let ack_1 = peers[0].execute_locally(first[1]);
+        let late_ack = peers[1].execute_locally(first[2]);
+        invoker.execute_locally(ack_0[0]);
+        invoker.execute_locally(ack_1[0]);
+        assert!(!invoker.enq_pending);
+
+        invoke(&mut invoker, 0, 2, 0);
+        invoker.execute_locally(late_ack[0]);
+        assert_eq!(invoker.enq_count, 0);
+        assert!(invoker.enq_pending);
+    }
+
+    #[test]
+    fn recovered_enqueues_are_applied_once() {
+        let config = Config {
+            ack_policy: AckPolicy::Majority,
+            ..Config::default()
+        };
+        let mut crashed = ProcessData::new(0, 3, &config);
+        let mut holder = ProcessData::new(1, 3, &config);
+        let mut missed = ProcessData::new(2, 3, &config);
+        let enq_reqs = invoke(&mut crashed, 0, 7, 0);
+        // Rank 0 crashed after its EnqReq reached rank 1 only
+        holder.execute_locally(enq_reqs[1]);
+
+        let relayed = holder.set_suspected(&[0]);
+        assert_eq!(relayed.len(), 1);
+        assert_eq!(relayed[0].receiver, 2);
+        // Suspecting the same rank again recovers nothing more
+        assert!(holder.set_suspected(&[0]).is_empty());
+
+        missed.execute_locally(relayed[0]);
+        assert_eq!(missed.local_queue.len(), 1);
+        // The original EnqReq turning up late is a repeat, only acked again
+        let ack = missed.execute_locally(enq_reqs[2]);
+        assert_eq!(ack.len(), 1);
+        assert_eq!(ack[0].message, 2);
+        assert_eq!(missed.local_queue.len(), 1);
+    }
+
+    #[test]
+    fn repeated_acks_count_once() {
+        let mut invoker = ProcessData::new(0, 2, &Config::default());
+        let mut peer = ProcessData::new(1, 2, &Config::default());
+        let enq_reqs = invoke(&mut invoker, 0, 3, 0);
+        let ack = peer.execute_locally(enq_reqs[1]);
+        let repeated = peer.execute_locally(enq_reqs[1]);
+        assert_eq!(peer.local_queue.len(), 1);
+        invoker.execute_locally(ack[0]);
+        invoker.execute_locally(repeated[0]);
+        assert_eq!(invoker.enq_count, 1);
+        assert!(invoker.enq_pending);
+    }
+
+    #[test]
+    fn paper_rule_advances_own_entry_on_receive() {
+        let config = Config {
+            clock_rule: ClockRule::IncrementAndMerge,
+            ..Config::default()
+        };
+        let mut node = ProcessData::new(0, 2, &config);
+        node.update_ts(&VectorClock::try_from(vec![0, 3]).unwrap());
+        assert_eq!(Vec::from(node.timestamp), vec![1, 3]);
+
+        let mut node = ProcessData::new(0, 2, &Config::default());
+        node.update_ts(&VectorClock::try_from(vec![0, 3]).unwrap());
+        assert_eq!(Vec::from(node.timestamp), vec![0, 3]);
+    }
+
+    #[test]
+    fn suspected_members_are_not_expected_to_execute_dequeues() {
+        let config = Config {
+            ack_policy: AckPolicy::Majority,
+            ..Config::default()
+        };
+        let mut node = ProcessData::new(0, 5, &config);
+        assert_eq!(node.executing_replicas(), 5);
+        node.set_suspected(&[3, 4]);
+        assert_eq!(node.executing_replicas(), 3);
+        node.set_suspected(&[2, 3, 4]);
+        assert_eq!(node.executing_replicas(), 3);
+
+        let mut node = ProcessData::new(0, 5, &Config::default());
+        node.set_suspected(&[3, 4]);
+        assert_eq!(node.executing_replicas(), 5);
+    }
+
+    // Processes joined by FIFO channels, driven one delivery at a time
+    struct Cluster {
+        nodes: Vec<ProcessData>,
+        channels: BTreeMap<(Rank, Rank), VecDeque<MessagePayload>>,
+    }
+
+    impl Cluster {
+        fn new(config: &Config, size: i32) -> Self {
+            Cluster {
+                nodes: (0..size)
+                    .map(|rank| ProcessData::new(rank, size, config))
+                    .collect(),
+                channels: BTreeMap::new(),
+            }
+        }
+
+        fn post(&mut self, messages: Vec<MessagePayload>) {
+            for message in messages {
+                self.channels
+                    .entry((message.sender, message.receiver))
+                    .or_default()
+                    .push_back(message);
+            }
+        }
+
+        fn invoke(&mut self, rank: Rank, code: i32, value: i32) {
+            let sent = invoke(&mut self.nodes[rank as usize], code, value, rank);
+            self.post(sent);
+        }
+
+        // Delivers the oldest message on the channel from `sender` to `receiver`
+        fn deliver(&mut self, sender: Rank, receiver: Rank) -> MessagePayload {
+            let message = self
+                .channels
+                .get_mut(&(sender, receiver))
+                .and_then(VecDeque::pop_front)
+                .unwrap();
+            let sent = self.nodes[receiver as usize].execute_locally(message);
+            self.post(sent);
+            message
+        }
+
+        fn settle(&mut self) {
+            while let Some(channel) = self
+                .channels
+                .iter()
+                .find(|(_, queue)| !queue.is_empty())
+                .map(|(&channel, _)| channel)
+            {
+                self.deliver(channel.0, channel.1);
+            }
+        }
+
+        fn queue(&self, rank: Rank) -> Vec<(i32, Rank, VectorClock)> {
+            self.nodes[rank as usize].local_queue.iter().collect()
+        }
+
+        fn pending(&self, rank: Rank) -> Vec<(VectorClock, Rank, bool)> {
+            self.nodes[rank as usize]
+                .pending_dequeues
+                .iter()
+                .map(|cl| (cl.ts, cl.invoker, cl.handled))
+                .collect()
+        }
+    }
+
+    fn with_members(initial_members: i32) -> Config {
+        Config {
+            initial_members: Some(initial_members),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn a_joining_process_receives_the_queue_and_pending_dequeues() {
+        let mut cluster = Cluster::new(&with_members(2), 3);
+        cluster.invoke(0, 0, 5);
+        cluster.settle();
+        cluster.invoke(1, 0, 6);
+        cluster.settle();
+        cluster.invoke(1, 3, 0);
+        cluster.settle();
+        assert_eq!(cluster.queue(0).len(), 1);
+        assert_eq!(cluster.pending(0).len(), 1);
+
+        cluster.invoke(2, 7, 0);
+        cluster.settle();
+        for node in cluster.nodes.iter() {
+            assert_eq!(node.membership.ranks(), vec![0, 1, 2]);
+            assert!(!node.membership.reconfiguring);
+        }
+        assert_eq!(cluster.queue(2), cluster.queue(0));
+        assert_eq!(cluster.pending(2), cluster.pending(0));
+
+        // The new member takes part in the next Dequeue and removes the same element
+        cluster.invoke(2, 3, 0);
+        cluster.settle();
+        for rank in 0..3 {
+            assert!(cluster.queue(rank).is_empty());
+        }
+        assert_eq!(cluster.nodes[2].completed_dequeues, 1);
+    }
+
+    #[test]
+    fn a_leaving_member_drops_its_replica() {
+        let mut cluster = Cluster::new(&Config::default(), 3);
+        cluster.invoke(0, 0, 5);
+        cluster.settle();
+
+        cluster.invoke(2, 8, 0);
+        cluster.settle();
+        for node in cluster.nodes.iter() {
+            assert_eq!(node.membership.ranks(), vec![0, 1]);
+        }
+        assert!(cluster.queue(2).is_empty());
+        assert_eq!(cluster.queue(1), cluster.queue(0));
+
+        // Later Enqueues reach the remaining members only
+        cluster.invoke(1, 0, 6);
+        assert!(!cluster.channels.contains_key(&(1, 2)));
+        cluster.settle();
+        assert_eq!(cluster.queue(0).len(), 2);
+        assert!(cluster.queue(2).is_empty());
+        // Invocations at a former member are rejected
+        cluster.invoke(2, 0, 7);
+        assert!(cluster
+            .channels
+            .iter()
+            .all(|(&(sender, _), queue)| sender != 2 || queue.is_empty()));
+        assert_eq!(cluster.nodes[2].completions.len(), 1);
+    }
+
+    #[test]
+    fn invocations_during_a_view_change_are_held_until_it_commits() {
+        let mut cluster = Cluster::new(&with_members(2), 3);
+        cluster.invoke(2, 7, 0);
+        cluster.deliver(2, 0);
+        // ViewChange at rank 1 only; rank 0 still has to see its own
+        cluster.deliver(0, 1);
+        assert!(cluster.nodes[1].membership.reconfiguring);
+
+        cluster.invoke(1, 0, 5);
+        assert_eq!(cluster.nodes[1].membership.deferred.len(), 1);
+        assert!(!cluster.nodes[1].enq_pending);
+        assert!(cluster.queue(0).is_empty());
+
+        cluster.settle();
+        assert!(cluster.nodes[1].membership.deferred.is_empty());
+        // Issued in the new configuration, so the joined process holds the element too
+        for rank in 0..3 {
+            assert_eq!(
+                cluster.queue(rank).iter().map(|e| e.0).collect::<Vec<_>>(),
+                vec![5]
+            );
+        }
+        assert_eq!(cluster.nodes[1].completed_enqueues, 1);
+    }
+
+    #[test]
+    fn the_coordinator_commits_once_every_member_acked_in_any_order() {
+        let mut cluster = Cluster::new(&with_members(2), 3);
+        // Rank 1's Enqueue is still in flight when the change is announced
+        cluster.invoke(1, 0, 5);
+        cluster.invoke(2, 7, 0);
+        cluster.deliver(2, 0);
+        cluster.deliver(0, 0);
+        cluster.deliver(0, 1);
+        assert!(cluster.nodes[1].enq_pending);
+        assert!(!cluster.nodes[1].membership.ack_sent);
+
+        // The coordinator's own ack arrives first and is not enough
+        let ack = cluster.deliver(0, 0);
+        assert_eq!(ack.message, 11);
+        assert_eq!(cluster.nodes[0].membership.view_acks, 1);
+        assert!(!cluster.channels.contains_key(&(0, 2)));
+
+        // Rank 1 acks once its Enqueue completed, and only then does the commit go out
+        cluster.settle();
+        assert_eq!(cluster.nodes[0].membership.view_acks, 0);
+        for node in cluster.nodes.iter() {
+            assert_eq!(node.membership.ranks(), vec![0, 1, 2]);
+        }
+        assert_eq!(cluster.queue(2), cluster.queue(0));
+        assert_eq!(cluster.queue(2).len(), 1);
+    }
+}
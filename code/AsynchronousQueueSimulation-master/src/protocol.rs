@@ -0,0 +1,455 @@
+use mpi::Rank;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use crate::admin::{AdminCommand, AdminRequest};
+use crate::failure_detector::SharedHealth;
+use crate::message_payload::{MessagePayload, VectorClock};
+use crate::metrics::SharedConnectionStats;
+use crate::process_data::OpOutcome;
+
+// Versioned JSON protocol on the client port, one object per line:
+//
+//   {"v":1,"id":7,"op":"enqueue","value":5}
+//   {"v":1,"id":7,"ok":true,"result":{"value":5}}
+//   {"v":1,"id":8,"ok":false,"error":{"code":"timeout","message":"..."}}
+//
+// `id` is chosen by the client and echoed back unchanged. Enqueue and Dequeue run at the
+// process that owns the port and the response is written once the operation has returned.
+// Join, Leave and Snapshot are accepted without waiting, and admin command names return
+// the same JSON as the plain-text admin commands.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Work handed from a client thread to the MPI thread. `reply` is set when the client waits
+// for the outcome; main tags the invocation with an op id to route it back.
+pub struct ClientRequest {
+    pub message: MessagePayload,
+    pub reply: Option<Sender<OpOutcome>>,
+}
+
+// Everything a client connection needs to reach the MPI thread
+#[derive(Clone)]
+pub struct ClientContext {
+    pub rank: Rank,
+    pub tx: Sender<ClientRequest>,
+    pub admin_tx: Sender<AdminRequest>,
+    pub health: SharedHealth,
+    pub timeout: Duration,
+    pub connections: SharedConnectionStats,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    ParseError,
+    UnsupportedVersion,
+    UnknownOp,
+    // HTTP only: the endpoint exists but not for the request's method
+    MethodNotAllowed,
+    InvalidArgument,
+    Rejected,
+    Timeout,
+    Unavailable,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ProtocolError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ProtocolError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    v: u32,
+    #[serde(default)]
+    id: Value,
+    op: String,
+    value: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+struct Response {
+    v: u32,
+    id: Value,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ProtocolError>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+}
+
+// Named operations of protocol version 1
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Enqueue(i32),
+    Dequeue,
+    Join,
+    Leave,
+    Snapshot(i32),
+    Admin(AdminCommand),
+}
+
+impl Operation {
+    fn parse(op: &str, value: Option<i32>) -> Result<Self, ProtocolError> {
+        let require = |value: Option<i32>| {
+            value.ok_or_else(|| {
+                ProtocolError::new(
+                    ErrorCode::InvalidArgument,
+                    format!("\"{}\" requires an integer \"value\"", op),
+                )
+            })
+        };
+        match op {
+            "enqueue" => Ok(Operation::Enqueue(require(value)?)),
+            "dequeue" => Ok(Operation::Dequeue),
+            "join" => Ok(Operation::Join),
+            "leave" => Ok(Operation::Leave),
+            "snapshot" => Ok(Operation::Snapshot(require(value)?)),
+            _ => op.parse().map(Operation::Admin).map_err(|_| {
+                ProtocolError::new(ErrorCode::UnknownOp, format!("Unknown op \"{}\"", op))
+            }),
+        }
+    }
+
+    // Invocation message for the operation, invoked at `rank`
+    fn invocation(&self, rank: Rank) -> Option<MessagePayload> {
+        let (code, value) = match *self {
+            Operation::Enqueue(value) => (0, value),
+            Operation::Dequeue => (3, 0),
+            Operation::Join => (7, 0),
+            Operation::Leave => (8, 0),
+            Operation::Snapshot(id) => (16, id),
+            Operation::Admin(_) => return None,
+        };
+        Some(invocation(code, value, rank))
+    }
+}
+
+// Invocation message with the given code, invoked by a client of `rank`
+pub fn invocation(code: i32, value: i32, rank: Rank) -> MessagePayload {
+    MessagePayload::new(code, value, rank, rank, rank, VectorClock::default())
+}
+
+// Result of an Enqueue or Dequeue as the protocol reports it
+pub fn outcome_result(outcome: OpOutcome) -> Result<Value, ProtocolError> {
+    match outcome {
+        OpOutcome::Enqueued => Ok(json!({})),
+        OpOutcome::Dequeued(value) => Ok(json!({ "value": value })),
+        OpOutcome::Rejected(reason) => Err(ProtocolError::new(ErrorCode::Rejected, reason)),
+    }
+}
+
+// Sends `message` to the MPI thread and blocks until its operation returns
+pub fn invoke(ctx: &ClientContext, message: MessagePayload) -> Result<OpOutcome, ProtocolError> {
+    let (reply_tx, reply_rx) = mpsc::channel();
+    ctx.tx
+        .send(ClientRequest {
+            message,
+            reply: Some(reply_tx),
+        })
+        .map_err(|_| ProtocolError::new(ErrorCode::Unavailable, "MPI thread has stopped"))?;
+    reply_rx.recv_timeout(ctx.timeout).map_err(|e| match e {
+        RecvTimeoutError::Timeout => ProtocolError::new(
+            ErrorCode::Timeout,
+            format!("No response within {} ms", ctx.timeout.as_millis()),
+        ),
+        RecvTimeoutError::Disconnected => {
+            ProtocolError::new(ErrorCode::Unavailable, "MPI thread has stopped")
+        }
+    })
+}
+
+// Sends an admin command to the MPI thread and returns its JSON reply
+pub fn admin(ctx: &ClientContext, command: AdminCommand) -> Result<Value, ProtocolError> {
+    let (reply_tx, reply_rx) = mpsc::channel();
+    ctx.admin_tx
+        .send(AdminRequest {
+            command,
+            reply: reply_tx,
+        })
+        .map_err(|_| ProtocolError::new(ErrorCode::Unavailable, "MPI thread has stopped"))?;
+    let reply = reply_rx.recv_timeout(ctx.timeout).map_err(|e| match e {
+        RecvTimeoutError::Timeout => ProtocolError::new(
+            ErrorCode::Timeout,
+            format!("No admin reply within {} ms", ctx.timeout.as_millis()),
+        ),
+        RecvTimeoutError::Disconnected => {
+            ProtocolError::new(ErrorCode::Unavailable, "MPI thread has stopped")
+        }
+    })?;
+    serde_json::from_str(&reply)
+        .map_err(|e| ProtocolError::new(ErrorCode::Unavailable, e.to_string()))
+}
+
+// Health warnings attached to responses so clients learn why an operation may stall
+pub fn warnings(health: &SharedHealth) -> Vec<String> {
+    let health = health.lock().unwrap();
+    let mut warnings = Vec::new();
+    if !health.suspected.is_empty() {
+        warnings.push(format!("suspected failed ranks {:?}", health.suspected));
+    }
+    warnings.extend(
+        health
+            .stalled
+            .iter()
+            .map(|stalled| format!("stalled: {}", stalled)),
+    );
+    warnings
+}
+
+fn run(ctx: &ClientContext, op: Operation) -> Result<Value, ProtocolError> {
+    match op {
+        Operation::Admin(command) => admin(ctx, command),
+        Operation::Enqueue(_) | Operation::Dequeue => {
+            let message = op
+                .invocation(ctx.rank)
+                .expect("queue operations have an invocation");
+            outcome_result(invoke(ctx, message)?)
+        }
+        _ => {
+            let message = op
+                .invocation(ctx.rank)
+                .expect("membership operations have an invocation");
+            ctx.tx
+                .send(ClientRequest {
+                    message,
+                    reply: None,
+                })
+                .map_err(|_| {
+                    ProtocolError::new(ErrorCode::Unavailable, "MPI thread has stopped")
+                })?;
+            Ok(json!({ "accepted": true }))
+        }
+    }
+}
+
+// Handles one JSON request line and returns the response line
+pub fn handle_line(ctx: &ClientContext, line: &str) -> String {
+    let (id, result) = match serde_json::from_str::<Request>(line) {
+        Err(e) => (
+            Value::Null,
+            Err(ProtocolError::new(ErrorCode::ParseError, e.to_string())),
+        ),
+        Ok(request) if request.v != PROTOCOL_VERSION => (
+            request.id,
+            Err(ProtocolError::new(
+                ErrorCode::UnsupportedVersion,
+                format!(
+                    "Protocol version {} is not supported, expected {}",
+                    request.v, PROTOCOL_VERSION
+                ),
+            )),
+        ),
+        Ok(request) => (
+            request.id,
+            Operation::parse(&request.op, request.value).and_then(|op| run(ctx, op)),
+        ),
+    };
+    let response = match result {
+        Ok(result) => Response {
+            v: PROTOCOL_VERSION,
+            id,
+            ok: true,
+            result: Some(result),
+            error: None,
+            warnings: warnings(&ctx.health),
+        },
+        Err(error) => Response {
+            v: PROTOCOL_VERSION,
+            id,
+            ok: false,
+            result: None,
+            error: Some(error),
+            warnings: warnings(&ctx.health),
+        },
+    };
+    serde_json::to_string(&response).expect("Failed to serialize response")
+}
+
+// Client contexts backed by a stand-in for the MPI thread, for testing the client-facing
+// servers without MPI
+#[cfg(test)]
+pub(crate) mod stub {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    // Every invocation received is recorded. Waiting clients get `answer(invocation)`, or no
+    // reply at all when it returns None; admin commands get `{"command":"<Debug name>"}`.
+    pub fn context(
+        answer: impl Fn(&MessagePayload) -> Option<OpOutcome> + Send + 'static,
+    ) -> (ClientContext, Arc<Mutex<Vec<MessagePayload>>>) {
+        let (tx, rx) = mpsc::channel::<ClientRequest>();
+        let (admin_tx, admin_rx) = mpsc::channel::<AdminRequest>();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = received.clone();
+        thread::spawn(move || {
+            // Replies never sent are kept so their clients time out instead of disconnecting
+            let mut unanswered = Vec::new();
+            for request in rx {
+                log.lock().unwrap().push(request.message);
+                if let Some(reply) = request.reply {
+                    match answer(&request.message) {
+                        Some(outcome) => {
+                            let _ = reply.send(outcome);
+                        }
+                        None => unanswered.push(reply),
+                    }
+                }
+            }
+        });
+        thread::spawn(move || {
+            for request in admin_rx {
+                let reply = json!({ "command": format!("{:?}", request.command) });
+                let _ = request.reply.send(reply.to_string());
+            }
+        });
+        let ctx = ClientContext {
+            rank: 2,
+            tx,
+            admin_tx,
+            health: Default::default(),
+            timeout: Duration::from_millis(200),
+            connections: Default::default(),
+        };
+        (ctx, received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answer(message: &MessagePayload) -> Option<OpOutcome> {
+        match (message.message, message.value) {
+            (0, 13) => Some(OpOutcome::Rejected(
+                "not a member of the current configuration",
+            )),
+            (0, 99) => None,
+            (0, _) => Some(OpOutcome::Enqueued),
+            _ => Some(OpOutcome::Dequeued(Some(5))),
+        }
+    }
+
+    fn exchange(line: &str) -> String {
+        let (ctx, _) = stub::context(answer);
+        handle_line(&ctx, line)
+    }
+
+    #[test]
+    fn answers_queue_operations() {
+        let (ctx, received) = stub::context(answer);
+        assert_eq!(
+            handle_line(&ctx, r#"{"v":1,"id":7,"op":"enqueue","value":5}"#),
+            r#"{"v":1,"id":7,"ok":true,"result":{}}"#
+        );
+        assert_eq!(
+            handle_line(&ctx, r#"{"v":1,"id":"x","op":"dequeue"}"#),
+            r#"{"v":1,"id":"x","ok":true,"result":{"value":5}}"#
+        );
+        assert_eq!(
+            handle_line(&ctx, r#"{"v":1,"op":"join"}"#),
+            r#"{"v":1,"id":null,"ok":true,"result":{"accepted":true}}"#
+        );
+        assert_eq!(
+            handle_line(&ctx, r#"{"v":1,"id":[1],"op":"clock"}"#),
+            r#"{"v":1,"id":[1],"ok":true,"result":{"command":"Clock"}}"#
+        );
+        let invocations: Vec<(i32, i32, Rank)> = received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| (m.message, m.value, m.invoker))
+            .collect();
+        assert_eq!(invocations, vec![(0, 5, 2), (3, 0, 2), (7, 0, 2)]);
+    }
+
+    #[test]
+    fn rejects_other_protocol_versions() {
+        assert_eq!(
+            exchange(r#"{"v":2,"id":1,"op":"dequeue"}"#),
+            r#"{"v":1,"id":1,"ok":false,"error":{"code":"unsupported_version","message":"Protocol version 2 is not supported, expected 1"}}"#
+        );
+    }
+
+    #[test]
+    fn reports_malformed_requests_without_an_id() {
+        assert_eq!(
+            exchange(r#"{"v":1,"id":4,"op""#),
+            r#"{"v":1,"id":null,"ok":false,"error":{"code":"parse_error","message":"EOF while parsing an object at line 1 column 18"}}"#
+        );
+        assert_eq!(
+            exchange(r#"{"v":1,"id":4}"#),
+            r#"{"v":1,"id":null,"ok":false,"error":{"code":"parse_error","message":"missing field `op` at line 1 column 14"}}"#
+        );
+    }
+
+    #[test]
+    fn reports_unknown_ops_and_missing_values() {
+        assert_eq!(
+            exchange(r#"{"v":1,"id":2,"op":"peek"}"#),
+            r#"{"v":1,"id":2,"ok":false,"error":{"code":"unknown_op","message":"Unknown op \"peek\""}}"#
+        );
+        assert_eq!(
+            exchange(r#"{"v":1,"id":3,"op":"enqueue"}"#),
+            r#"{"v":1,"id":3,"ok":false,"error":{"code":"invalid_argument","message":"\"enqueue\" requires an integer \"value\""}}"#
+        );
+    }
+
+    #[test]
+    fn reports_rejected_and_timed_out_operations() {
+        assert_eq!(
+            exchange(r#"{"v":1,"id":5,"op":"enqueue","value":13}"#),
+            r#"{"v":1,"id":5,"ok":false,"error":{"code":"rejected","message":"not a member of the current configuration"}}"#
+        );
+        assert_eq!(
+            exchange(r#"{"v":1,"id":6,"op":"enqueue","value":99}"#),
+            r#"{"v":1,"id":6,"ok":false,"error":{"code":"timeout","message":"No response within 200 ms"}}"#
+        );
+    }
+
+    #[test]
+    fn reports_a_stopped_mpi_thread() {
+        let (tx, _) = mpsc::channel();
+        let (admin_tx, _) = mpsc::channel();
+        let ctx = ClientContext {
+            rank: 0,
+            tx,
+            admin_tx,
+            health: Default::default(),
+            timeout: Duration::from_millis(200),
+            connections: Default::default(),
+        };
+        assert_eq!(
+            handle_line(&ctx, r#"{"v":1,"id":1,"op":"dequeue"}"#),
+            r#"{"v":1,"id":1,"ok":false,"error":{"code":"unavailable","message":"MPI thread has stopped"}}"#
+        );
+    }
+
+    #[test]
+    fn attaches_health_warnings() {
+        let (ctx, _) = stub::context(answer);
+        {
+            let mut health = ctx.health.lock().unwrap();
+            health.suspected = vec![1];
+            health.stalled = vec!["dequeue waiting on rank 1".to_string()];
+        }
+        assert_eq!(
+            handle_line(&ctx, r#"{"v":1,"id":1,"op":"enqueue","value":5}"#),
+            r#"{"v":1,"id":1,"ok":true,"result":{},"warnings":["suspected failed ranks [1]","stalled: dequeue waiting on rank 1"]}"#
+        );
+    }
+}
@@ -0,0 +1,463 @@
+use mpi::Rank;
+use std::io::{self, BufRead, Write};
+
+use crate::config::Config;
+use crate::logging;
+use crate::message_payload::{MessagePayload, VectorClock};
+use crate::process_data::ProcessData;
+
+// Interactive shell that runs a cluster inside one process, without MPI, so the algorithm
+// can be stepped by hand (`async_queue --repl 3`). Every message a node sends goes on the
+// simulated network and stays there until it is delivered with `deliver`, `step` or `run`,
+// so any interleaving can be reproduced. Channels stay FIFO as MPI's are: a message is only
+// delivered after the earlier ones from the same sender to the same receiver, so holding one
+// also holds those behind it. The send rule is the one in main: an Enqueue invocation waits
+// while its process is locked.
+const HELP: &str = "commands:
+  enq <rank> <value>   invoke Enqueue(value) at rank
+  deq <rank>           invoke Dequeue at rank
+  join <rank>          invoke Join at a spare rank
+  leave <rank>         invoke Leave at a member
+  net                  list messages in flight
+  deliver <id>         deliver one message
+  hold <id>            keep a message, and later ones on its channel, from step/run
+  release <id>         undo hold
+  step                 deliver the oldest message that is not held
+  run                  deliver messages until only held ones remain
+  show [rank]          print the state of every node, or of one
+  help                 print this text
+  quit";
+
+// Upper bound on deliveries per `run`, in case the messages never settle
+const RUN_LIMIT: usize = 10_000;
+
+struct InFlight {
+    id: usize,
+    message: MessagePayload,
+    held: bool,
+}
+
+struct Simulation {
+    nodes: Vec<ProcessData>,
+    // Messages produced by each node that the send rule has not released yet
+    outboxes: Vec<Vec<MessagePayload>>,
+    network: Vec<InFlight>,
+    next_id: usize,
+    next_op_id: i32,
+}
+
+fn clock(ts: &VectorClock) -> Vec<i32> {
+    Vec::from(*ts)
+}
+
+fn describe(message: &MessagePayload) -> String {
+    format!(
+        "{} {} -> {} value={} invoker={} ts={:?}",
+        message.kind(),
+        message.sender,
+        message.receiver,
+        message.value,
+        message.invoker,
+        clock(&message.time_stamp)
+    )
+}
+
+impl Simulation {
+    fn new(size: i32, config: &Config) -> Self {
+        Simulation {
+            nodes: (0..size)
+                .map(|rank| ProcessData::new(rank, size, config))
+                .collect(),
+            outboxes: vec![Vec::new(); size as usize],
+            network: Vec::new(),
+            next_id: 0,
+            next_op_id: 0,
+        }
+    }
+
+    fn rank(&self, arg: Option<&str>) -> Result<Rank, String> {
+        let rank: Rank = arg
+            .ok_or("missing rank")?
+            .parse()
+            .map_err(|_| "rank must be an integer")?;
+        if rank < 0 || rank as usize >= self.nodes.len() {
+            return Err(format!("rank must be below {}", self.nodes.len()));
+        }
+        Ok(rank)
+    }
+
+    // Queues a client invocation at `rank`, as `handle_client` does
+    fn invoke(&mut self, code: i32, value: i32, rank: Rank) {
+        let message = MessagePayload::new(code, value, rank, rank, rank, VectorClock::default())
+            .with_op_id(self.next_op_id);
+        self.next_op_id += 1;
+        self.outboxes[rank as usize].push(message);
+        self.flush(rank);
+    }
+
+    // Moves whatever the send rule allows from the outbox of `rank` onto the network
+    fn flush(&mut self, rank: Rank) {
+        let node = &mut self.nodes[rank as usize];
+        let outbox = &mut self.outboxes[rank as usize];
+        let mut i = 0;
+        while i < outbox.len() {
+            if outbox[i].message == 0 && node.locked {
+                i += 1;
+                continue;
+            }
+            if matches!(outbox[i].message, 0 | 3) {
+                node.locked = true;
+            }
+            let message = outbox.remove(i);
+            println!("  sent #{} {}", self.next_id, describe(&message));
+            self.network.push(InFlight {
+                id: self.next_id,
+                message,
+                held: false,
+            });
+            self.next_id += 1;
+        }
+    }
+
+    // The oldest message in flight on the channel of `message`
+    fn channel_head(&self, message: &MessagePayload) -> usize {
+        self.network
+            .iter()
+            .find(|in_flight| {
+                in_flight.message.sender == message.sender
+                    && in_flight.message.receiver == message.receiver
+            })
+            .map(|in_flight| in_flight.id)
+            .expect("the message itself is in flight")
+    }
+
+    fn deliver(&mut self, id: usize) -> Result<(), String> {
+        let index = self
+            .network
+            .iter()
+            .position(|in_flight| in_flight.id == id)
+            .ok_or_else(|| format!("no message #{} in flight", id))?;
+        let head = self.channel_head(&self.network[index].message);
+        if head != id {
+            return Err(format!("channels are FIFO, deliver #{} first", head));
+        }
+        let message = self.network.remove(index).message;
+        let rank = message.receiver;
+        println!("  delivered #{} {}", id, describe(&message));
+
+        let _span = logging::process_span(rank).entered();
+        let node = &mut self.nodes[rank as usize];
+        node.message_history.push(message);
+        let sent = node.execute_locally(message);
+        for completion in std::mem::take(&mut node.completions) {
+            println!(
+                "  P{} operation {} returned {:?}",
+                rank, completion.op_id, completion.outcome
+            );
+        }
+        // Replication checks are not simulated
+        node.executed_dequeues.clear();
+        node.digest.checkpoints.clear();
+        self.outboxes[rank as usize].extend(sent);
+        // Any node may have been unlocked, so the send rule is applied everywhere
+        for rank in 0..self.nodes.len() as Rank {
+            self.flush(rank);
+        }
+        self.show(Some(rank));
+        Ok(())
+    }
+
+    fn next_deliverable(&self) -> Option<usize> {
+        self.network
+            .iter()
+            .find(|in_flight| {
+                !in_flight.held && self.channel_head(&in_flight.message) == in_flight.id
+            })
+            .map(|in_flight| in_flight.id)
+    }
+
+    fn set_held(&mut self, id: usize, held: bool) -> Result<(), String> {
+        let in_flight = self
+            .network
+            .iter_mut()
+            .find(|in_flight| in_flight.id == id)
+            .ok_or_else(|| format!("no message #{} in flight", id))?;
+        in_flight.held = held;
+        Ok(())
+    }
+
+    fn show_network(&self) {
+        if self.network.is_empty() {
+            println!("  no messages in flight");
+        }
+        for in_flight in self.network.iter() {
+            println!(
+                "  #{}{} {}",
+                in_flight.id,
+                if in_flight.held { " (held)" } else { "" },
+                describe(&in_flight.message)
+            );
+        }
+    }
+
+    fn show(&self, only: Option<Rank>) {
+        for (rank, node) in self.nodes.iter().enumerate() {
+            if only.is_some_and(|only| only != rank as Rank) {
+                continue;
+            }
+            println!(
+                "  P{} clock={:?} member={} locked={} enq_pending={} enq_count={} deq_pending={}",
+                rank,
+                clock(&node.timestamp),
+                node.membership.is_member(rank as Rank),
+                node.locked,
+                node.enq_pending,
+                node.enq_count,
+                node.deq_pending
+            );
+            let queue: Vec<String> = node
+                .local_queue
+                .iter()
+                .map(|(value, invoker, ts)| format!("{}@P{}{:?}", value, invoker, clock(&ts)))
+                .collect();
+            println!("     queue=[{}]", queue.join(", "));
+            for list in node.pending_dequeues.iter().filter(|list| !list.handled) {
+                println!(
+                    "     dequeue P{}{:?} responses={:?}",
+                    list.invoker,
+                    clock(&list.ts),
+                    list.response_buffer
+                );
+            }
+            if !self.outboxes[rank].is_empty() {
+                let waiting: Vec<String> = self.outboxes[rank].iter().map(describe).collect();
+                println!("     waiting to send: {}", waiting.join("; "));
+            }
+        }
+    }
+
+    fn execute(&mut self, line: &str) -> Result<bool, String> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(true);
+        };
+        let id = |arg: Option<&str>| -> Result<usize, String> {
+            arg.ok_or("missing message id")?
+                .trim_start_matches('#')
+                .parse()
+                .map_err(|_| "message id must be an integer".to_string())
+        };
+        match command {
+            "enq" => {
+                let rank = self.rank(words.next())?;
+                let value = words
+                    .next()
+                    .ok_or("missing value")?
+                    .parse()
+                    .map_err(|_| "value must be an integer")?;
+                self.invoke(0, value, rank);
+            }
+            "deq" => {
+                let rank = self.rank(words.next())?;
+                self.invoke(3, 0, rank);
+            }
+            "join" => {
+                let rank = self.rank(words.next())?;
+                self.invoke(7, 0, rank);
+            }
+            "leave" => {
+                let rank = self.rank(words.next())?;
+                self.invoke(8, 0, rank);
+            }
+            "net" => self.show_network(),
+            "deliver" => self.deliver(id(words.next())?)?,
+            "hold" => self.set_held(id(words.next())?, true)?,
+            "release" => self.set_held(id(words.next())?, false)?,
+            "step" => match self.next_deliverable() {
+                Some(id) => self.deliver(id)?,
+                None => println!("  nothing to deliver"),
+            },
+            "run" => {
+                let mut delivered = 0;
+                while let Some(id) = self.next_deliverable() {
+                    if delivered == RUN_LIMIT {
+                        return Err(format!("stopped after {} deliveries", RUN_LIMIT));
+                    }
+                    self.deliver(id)?;
+                    delivered += 1;
+                }
+                println!("  delivered {} messages", delivered);
+            }
+            "show" => match words.next() {
+                Some(rank) => self.show(Some(self.rank(Some(rank))?)),
+                None => self.show(None),
+            },
+            "help" => println!("{}", HELP),
+            "quit" | "exit" => return Ok(false),
+            _ => return Err(format!("unknown command {}, try help", command)),
+        }
+        Ok(true)
+    }
+}
+
+pub fn run(size: i32, config: &Config) {
+    let mut simulation = Simulation::new(size, config);
+    println!("Simulating {} processes, type help for commands", size);
+    let stdin = io::stdin();
+    let mut line = String::new();
+    loop {
+        print!("> ");
+        let _ = io::stdout().flush();
+        line.clear();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => match simulation.execute(&line) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => println!("error: {}", e),
+            },
+            Err(e) => {
+                println!("Failed to read input: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn simulation(size: i32, script: &[&str]) -> Simulation {
+        let mut simulation = Simulation::new(size, &Config::default());
+        for line in script {
+            assert_eq!(simulation.execute(line), Ok(true), "{}", line);
+        }
+        simulation
+    }
+
+    fn queue(simulation: &Simulation, rank: usize) -> Vec<i32> {
+        simulation.nodes[rank]
+            .local_queue
+            .iter()
+            .map(|(value, _, _)| value)
+            .collect()
+    }
+
+    #[test]
+    fn run_settles_every_replica() {
+        let mut simulation = simulation(3, &["enq 0 5", "enq 1 6", "run", "deq 2", "run"]);
+        for rank in 0..3 {
+            assert_eq!(queue(&simulation, rank).len(), 1);
+            assert!(!simulation.nodes[rank].locked);
+        }
+        assert_eq!(simulation.nodes[2].completed_dequeues, 1);
+        assert!(simulation.network.is_empty());
+        assert_eq!(simulation.execute("quit"), Ok(false));
+    }
+
+    // Id of the message in flight with the given code and channel
+    fn in_flight(simulation: &Simulation, code: i32, sender: Rank, receiver: Rank) -> usize {
+        simulation
+            .network
+            .iter()
+            .find(|in_flight| {
+                let message = &in_flight.message;
+                (message.message, message.sender, message.receiver) == (code, sender, receiver)
+            })
+            .map(|in_flight| in_flight.id)
+            .unwrap()
+    }
+
+    #[test]
+    fn a_held_message_blocks_step() {
+        let mut simulation = simulation(2, &["enq 0 5", "step"]);
+        let enq_req = in_flight(&simulation, 1, 0, 1);
+        simulation.execute(&format!("hold {}", enq_req)).unwrap();
+        // Rank 0's own EnqReq and EnqAck, then nothing but the held message
+        simulation.execute("step").unwrap();
+        simulation.execute("step").unwrap();
+        assert_eq!(queue(&simulation, 0), vec![5]);
+        simulation.execute("step").unwrap();
+        assert_eq!(simulation.network.len(), 1);
+        assert!(queue(&simulation, 1).is_empty());
+        assert!(simulation.nodes[0].enq_pending);
+        simulation.execute("run").unwrap();
+        assert_eq!(simulation.network.len(), 1);
+
+        simulation.execute(&format!("release {}", enq_req)).unwrap();
+        simulation.execute("run").unwrap();
+        assert_eq!(queue(&simulation, 1), vec![5]);
+        assert!(!simulation.nodes[0].enq_pending);
+    }
+
+    #[test]
+    fn held_messages_also_hold_later_ones_on_their_channel() {
+        let mut simulation = simulation(2, &["enq 0 5", "step"]);
+        let enq_req = in_flight(&simulation, 1, 0, 1);
+        simulation.execute(&format!("hold {}", enq_req)).unwrap();
+        simulation.execute("enq 1 6").unwrap();
+        let invocation = in_flight(&simulation, 0, 1, 1);
+        simulation
+            .execute(&format!("deliver {}", invocation))
+            .unwrap();
+        let enq_req_1 = in_flight(&simulation, 1, 1, 0);
+        simulation
+            .execute(&format!("deliver {}", enq_req_1))
+            .unwrap();
+        // Rank 0's ack of rank 1's Enqueue queues up behind the held EnqReq
+        let enq_ack = in_flight(&simulation, 2, 0, 1);
+        simulation.execute("run").unwrap();
+        assert_eq!(
+            simulation.execute(&format!("deliver {}", enq_ack)),
+            Err(format!("channels are FIFO, deliver #{} first", enq_req))
+        );
+        assert!(simulation.nodes[1].enq_pending);
+        assert_eq!(queue(&simulation, 1), vec![6]);
+
+        simulation.execute(&format!("release {}", enq_req)).unwrap();
+        simulation.execute("run").unwrap();
+        assert!(!simulation.nodes[1].enq_pending);
+        assert_eq!(queue(&simulation, 1), queue(&simulation, 0));
+        assert_eq!(queue(&simulation, 1).len(), 2);
+    }
+
+    #[test]
+    fn a_locked_node_keeps_its_enqueue_in_the_outbox() {
+        let mut simulation = simulation(2, &["enq 0 5", "enq 0 6"]);
+        assert!(simulation.nodes[0].locked);
+        let waiting: Vec<(i32, i32)> = simulation.outboxes[0]
+            .iter()
+            .map(|m| (m.message, m.value))
+            .collect();
+        assert_eq!(waiting, vec![(0, 6)]);
+        // Only the first invocation went out
+        assert_eq!(simulation.network.len(), 1);
+        simulation.execute("run").unwrap();
+        assert!(simulation.outboxes[0].is_empty());
+        assert_eq!(queue(&simulation, 0), vec![5, 6]);
+        assert_eq!(queue(&simulation, 1), vec![5, 6]);
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        let mut simulation = simulation(2, &[]);
+        assert_eq!(
+            simulation.execute("enq 2 5"),
+            Err("rank must be below 2".to_string())
+        );
+        assert_eq!(
+            simulation.execute("enq 0"),
+            Err("missing value".to_string())
+        );
+        assert_eq!(
+            simulation.execute("deliver 0"),
+            Err("no message #0 in flight".to_string())
+        );
+        assert_eq!(
+            simulation.execute("pop"),
+            Err("unknown command pop, try help".to_string())
+        );
+        assert_eq!(simulation.execute("   "), Ok(true));
+    }
+}
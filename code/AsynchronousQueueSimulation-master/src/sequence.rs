@@ -0,0 +1,312 @@
+use mpi::Rank;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::message_payload::{MessagePayload, VectorClock};
+use crate::wal::WriteAheadLog;
+
+// Sequence diagrams of recorded messages, e.g.
+//
+//   async_queue --diagram mermaid --trace wal/rank0.wal --trace wal/rank1.wal \
+//       --operation enq:0:5
+//
+// A trace is a write-ahead log or the JSON array returned by the `history` admin command;
+// both list the messages one rank received, in order. Traces of several ranks are merged
+// topologically: each step takes a head that no other head happens before, so a message
+// is never drawn before one it causally follows, and each rank keeps its own order.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DiagramFormat {
+    #[default]
+    Mermaid,
+    PlantUml,
+    Graphviz,
+}
+
+impl FromStr for DiagramFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mermaid" => Ok(DiagramFormat::Mermaid),
+            "plantuml" => Ok(DiagramFormat::PlantUml),
+            "graphviz" | "dot" => Ok(DiagramFormat::Graphviz),
+            _ => Err(format!("Unknown diagram format: {}", s)),
+        }
+    }
+}
+
+// The messages of one operation: an Enqueue is identified by its invoker and value, a
+// Dequeue by its invoker and timestamp
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OperationFilter {
+    Enqueue { invoker: Rank, value: i32 },
+    Dequeue { invoker: Rank, ts: VectorClock },
+}
+
+impl FromStr for OperationFilter {
+    type Err = String;
+
+    // `enq:<invoker>:<value>` or `deq:<invoker>:<c0>,<c1>,...`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid operation {}", s);
+        let mut parts = s.splitn(3, ':');
+        let (kind, invoker, rest) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(kind), Some(invoker), Some(rest)) => (kind, invoker, rest),
+            _ => return Err(invalid()),
+        };
+        let invoker = invoker.parse().map_err(|_| invalid())?;
+        match kind {
+            "enq" => Ok(OperationFilter::Enqueue {
+                invoker,
+                value: rest.parse().map_err(|_| invalid())?,
+            }),
+            "deq" => {
+                let entries = rest
+                    .split(',')
+                    .map(|entry| entry.trim().parse())
+                    .collect::<Result<Vec<i32>, _>>()
+                    .map_err(|_| invalid())?;
+                Ok(OperationFilter::Dequeue {
+                    invoker,
+                    ts: VectorClock::try_from(entries)?,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl OperationFilter {
+    fn matches(&self, message: &MessagePayload) -> bool {
+        match *self {
+            OperationFilter::Enqueue { invoker, value } => {
+                matches!(message.message, 0..=2)
+                    && message.invoker == invoker
+                    && message.value == value
+            }
+            OperationFilter::Dequeue { invoker, ts } => {
+                // The invocation itself carries no timestamp yet
+                matches!(message.message, 4 | 5)
+                    && message.invoker == invoker
+                    && message.time_stamp == ts
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DiagramOptions {
+    pub format: DiagramFormat,
+    pub traces: Vec<PathBuf>,
+    // Positions in the merged trace, end exclusive
+    pub positions: Option<(usize, usize)>,
+    pub operation: Option<OperationFilter>,
+}
+
+// Reads a write-ahead log (JSON lines) or a JSON array of messages
+pub fn load_trace(path: &Path) -> io::Result<Vec<MessagePayload>> {
+    let contents = fs::read_to_string(path)?;
+    if contents.trim_start().starts_with('[') {
+        return serde_json::from_str(&contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+    Ok(WriteAheadLog::read(path)?
+        .into_iter()
+        .map(|entry| entry.message)
+        .collect())
+}
+
+// Happens-before is a strict partial order, so some head always qualifies. Ties between
+// concurrent heads go to the smallest timestamp, then to the earliest trace.
+fn merge(traces: Vec<Vec<MessagePayload>>) -> Vec<MessagePayload> {
+    let mut heads = vec![0; traces.len()];
+    let mut merged = Vec::new();
+    loop {
+        let live: Vec<usize> = (0..traces.len())
+            .filter(|&t| heads[t] < traces[t].len())
+            .collect();
+        let head = |t: usize| traces[t][heads[t]].time_stamp;
+        let next = live
+            .iter()
+            .copied()
+            .filter(|&t| !live.iter().any(|&u| head(u).happens_before(&head(t))))
+            .min_by(|&a, &b| head(a).cmp(&head(b)).then(a.cmp(&b)));
+        match next {
+            Some(t) => {
+                merged.push(traces[t][heads[t]]);
+                heads[t] += 1;
+            }
+            None => return merged,
+        }
+    }
+}
+
+fn label(message: &MessagePayload) -> String {
+    format!(
+        "{}({}) inv=P{} ts={:?}",
+        message.kind(),
+        message.value,
+        message.invoker,
+        Vec::from(message.time_stamp)
+    )
+}
+
+pub fn render(messages: &[MessagePayload], format: DiagramFormat) -> String {
+    let participants: BTreeSet<Rank> = messages
+        .iter()
+        .flat_map(|message| [message.sender, message.receiver])
+        .collect();
+    let mut out = String::new();
+    match format {
+        DiagramFormat::Mermaid => {
+            writeln!(out, "sequenceDiagram").unwrap();
+            for rank in participants.iter() {
+                writeln!(out, "    participant P{}", rank).unwrap();
+            }
+            for message in messages {
+                writeln!(
+                    out,
+                    "    P{}->>P{}: {}",
+                    message.sender,
+                    message.receiver,
+                    label(message)
+                )
+                .unwrap();
+            }
+        }
+        DiagramFormat::PlantUml => {
+            writeln!(out, "@startuml").unwrap();
+            for rank in participants.iter() {
+                writeln!(out, "participant P{}", rank).unwrap();
+            }
+            for message in messages {
+                writeln!(
+                    out,
+                    "P{} -> P{} : {}",
+                    message.sender,
+                    message.receiver,
+                    label(message)
+                )
+                .unwrap();
+            }
+            writeln!(out, "@enduml").unwrap();
+        }
+        DiagramFormat::Graphviz => {
+            // One row of points per message, chained into a vertical lifeline per rank
+            writeln!(out, "digraph sequence {{").unwrap();
+            writeln!(out, "    node [shape=point];").unwrap();
+            writeln!(out, "    edge [arrowhead=none, style=dashed];").unwrap();
+            let row = |step: usize| -> String {
+                participants
+                    .iter()
+                    .map(|rank| format!("P{}_{}", rank, step))
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            };
+            for rank in participants.iter() {
+                writeln!(out, "    P{}_0 [shape=box, label=\"P{}\"];", rank, rank).unwrap();
+            }
+            writeln!(out, "    {{ rank=same; {}; }}", row(0)).unwrap();
+            for (i, message) in messages.iter().enumerate() {
+                let step = i + 1;
+                writeln!(out, "    {{ rank=same; {}; }}", row(step)).unwrap();
+                for rank in participants.iter() {
+                    writeln!(out, "    P{}_{} -> P{}_{};", rank, i, rank, step).unwrap();
+                }
+                writeln!(
+                    out,
+                    "    P{}_{} -> P{}_{} [style=solid, arrowhead=normal, constraint=false, label=\"{}\"];",
+                    message.sender,
+                    step,
+                    message.receiver,
+                    step,
+                    label(message)
+                )
+                .unwrap();
+            }
+            writeln!(out, "}}").unwrap();
+        }
+    }
+    out
+}
+
+pub fn run(options: &DiagramOptions) -> io::Result<()> {
+    let traces = options
+        .traces
+        .iter()
+        .map(|path| load_trace(path))
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut messages = merge(traces);
+    if let Some((from, to)) = options.positions {
+        let to = to.min(messages.len());
+        messages = messages[from.min(to)..to].to_vec();
+    }
+    if let Some(operation) = &options.operation {
+        messages.retain(|message| operation.matches(message));
+    }
+    print!("{}", render(&messages, options.format));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(code: i32, receiver: Rank, ts: &[i32]) -> MessagePayload {
+        let ts = VectorClock::try_from(ts.to_vec()).unwrap();
+        MessagePayload::new(code, 0, 0, 0, receiver, ts)
+    }
+
+    #[test]
+    fn merge_never_draws_a_message_before_one_it_follows() {
+        let invocation = MessagePayload::new(0, 5, 0, 0, 0, VectorClock::default());
+        let traces = vec![
+            vec![invocation, message(1, 0, &[1, 0]), message(2, 0, &[1, 0])],
+            vec![
+                message(1, 1, &[1, 0]),
+                message(4, 1, &[1, 2]),
+                message(5, 1, &[2, 3]),
+            ],
+            vec![message(4, 2, &[1, 2]), message(5, 2, &[2, 3])],
+        ];
+        let merged = merge(traces.clone());
+        assert_eq!(merged.len(), traces.iter().map(Vec::len).sum::<usize>());
+        for (i, earlier) in merged.iter().enumerate() {
+            for later in merged[i + 1..].iter() {
+                assert!(!later.time_stamp.happens_before(&earlier.time_stamp));
+            }
+        }
+        // Each rank keeps its own order
+        for trace in traces.iter() {
+            let positions: Vec<usize> = trace
+                .iter()
+                .map(|m| {
+                    merged
+                        .iter()
+                        .position(|n| {
+                            n.receiver == m.receiver
+                                && n.message == m.message
+                                && n.time_stamp == m.time_stamp
+                        })
+                        .unwrap()
+                })
+                .collect();
+            assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+    }
+
+    #[test]
+    fn merge_breaks_ties_by_timestamp_then_trace() {
+        let traces = vec![
+            vec![message(1, 0, &[0, 1])],
+            vec![message(1, 1, &[1, 0])],
+            vec![message(1, 2, &[0, 1])],
+        ];
+        let receivers: Vec<Rank> = merge(traces).iter().map(|m| m.receiver).collect();
+        assert_eq!(receivers, vec![0, 2, 1]);
+    }
+}
@@ -0,0 +1,124 @@
+use mpi::{Rank, Tag};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::error;
+
+use crate::message_payload::{MessagePayload, VectorClock};
+
+// Event log in the format read by ShiViz (https://bestchai.bitbucket.io/shiviz/), two
+// lines per event:
+//
+//   P1 {"P0":2,"P1":3}
+//   receive EnqReq value=5 invoker=0 from P0
+//
+// ShiViz needs the host's own entry to grow by exactly one per event, which the algorithm's
+// timestamps do not guarantee, so the log keeps its own vector clock. The clocks of a
+// frame's messages follow the frame as a second send on `CLOCK_TAG`, so nothing is added to
+// the messages themselves and untraced runs send nothing extra; every rank must trace or
+// none. Only rank 0 writes the parsing regex ShiViz expects at the top of the input, and
+// file names are zero-padded, so `cat rank*.shiviz` gives a loadable log.
+const HEADER: &str = "(?<host>\\S*) (?<clock>{.*})\\n(?<event>.*)\n\n";
+
+pub const CLOCK_TAG: Tag = 1;
+
+pub struct ShivizLog {
+    rank: Rank,
+    clock: VectorClock,
+    // Log clocks of the messages sent to each rank that have not left in a frame yet
+    stamps: BTreeMap<Rank, VecDeque<VectorClock>>,
+    out: BufWriter<File>,
+}
+
+impl ShivizLog {
+    pub fn path(dir: &Path, rank: Rank) -> PathBuf {
+        dir.join(format!("rank{:02}.shiviz", rank))
+    }
+
+    pub fn create(dir: &Path, rank: Rank, size: i32) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(Self::path(dir, rank))?);
+        if rank == 0 {
+            out.write_all(HEADER.as_bytes())?;
+        }
+        Ok(ShivizLog {
+            rank,
+            clock: VectorClock::new(size),
+            stamps: BTreeMap::new(),
+            out,
+        })
+    }
+
+    fn clock_json(&self) -> String {
+        let entries: Vec<String> = (0..self.clock.size)
+            .filter(|&i| self.clock.clock[i] > 0)
+            .map(|i| format!("\"P{}\":{}", i, self.clock.clock[i]))
+            .collect();
+        format!("{{{}}}", entries.join(","))
+    }
+
+    fn event(&mut self, description: String) {
+        self.clock.clock[self.rank as usize] += 1;
+        let entry = format!("P{} {}\n{}\n", self.rank, self.clock_json(), description);
+        if let Err(e) = self
+            .out
+            .write_all(entry.as_bytes())
+            .and_then(|_| self.out.flush())
+        {
+            error!("failed to write ShiViz log: {}", e);
+        }
+    }
+
+    // Logs the send and keeps the log clock until the message leaves in a frame
+    pub fn on_send(&mut self, message: &MessagePayload) {
+        self.event(format!(
+            "send {} value={} invoker={} to P{}",
+            message.kind(),
+            message.value,
+            message.invoker,
+            message.receiver
+        ));
+        self.stamps
+            .entry(message.receiver)
+            .or_default()
+            .push_back(self.clock);
+    }
+
+    // The log clocks to send after a frame, one per message. Heartbeats are not logged and
+    // get an empty clock.
+    pub fn frame_clocks(&mut self, receiver: Rank, frame: &[MessagePayload]) -> Vec<VectorClock> {
+        let stamps = self.stamps.entry(receiver).or_default();
+        frame
+            .iter()
+            .map(|message| match message.message {
+                6 => VectorClock::default(),
+                _ => stamps.pop_front().unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    // `clock` is the sender's log clock for this message
+    pub fn on_receive(&mut self, message: &MessagePayload, clock: &VectorClock) {
+        for i in 0..self.clock.size.min(clock.size) {
+            self.clock.clock[i] = self.clock.clock[i].max(clock.clock[i]);
+        }
+        self.event(format!(
+            "receive {} value={} invoker={} from P{}",
+            message.kind(),
+            message.value,
+            message.invoker,
+            message.sender
+        ));
+    }
+
+    // A change to the local replica, e.g. an element inserted or removed
+    pub fn on_apply(&mut self, description: String) {
+        self.event(format!("apply {}", description));
+    }
+
+    pub fn on_dequeues(&mut self, executed: &[(VectorClock, Rank, i32)]) {
+        for (_, invoker, value) in executed {
+            self.on_apply(format!("dequeue value={} invoker={}", value, invoker));
+        }
+    }
+}
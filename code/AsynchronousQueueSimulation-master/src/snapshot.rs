@@ -0,0 +1,150 @@
+use mpi::Rank;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::digest::ReplicaDigest;
+use crate::message_payload::VectorClock;
+use crate::process_data::ConfirmationList;
+
+// On-disk image of a replica's ProcessData, stored as JSON so it can also be inspected
+// offline. The message history is not part of a snapshot.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub rank: Rank,
+    pub world_size: i32,
+    pub timestamp: VectorClock,
+    pub enq_count: i32,
+    pub enq_pending: bool,
+    pub enq_ts: VectorClock,
+    pub enq_acked: Vec<bool>,
+    pub deq_pending: bool,
+    pub locked: bool,
+    pub local_queue: Vec<(i32, Rank, VectorClock)>,
+    pub pending_dequeues: Vec<ConfirmationList>,
+    pub members: Vec<bool>,
+    // Last write-ahead log entry reflected in this snapshot
+    pub applied_lsn: u64,
+    pub digest: ReplicaDigest,
+    pub applied_enqueues: Vec<i32>,
+}
+
+impl Snapshot {
+    pub fn path(dir: &Path, rank: Rank) -> PathBuf {
+        dir.join(format!("rank{}.json", rank))
+    }
+
+    // Writes to a temporary file first so a crash mid-write never leaves a torn snapshot.
+    // The file and the rename are both synced before returning, since the caller truncates
+    // the write-ahead log once this succeeds.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(dir)?;
+        let tmp_path = path.with_extension("json.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        File::open(dir)?.sync_all()
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::message_payload::MessagePayload;
+    use crate::process_data::ProcessData;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("async_queue_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn vc(entries: &[i32]) -> VectorClock {
+        VectorClock::try_from(entries.to_vec()).unwrap()
+    }
+
+    // Rank 0 of three with two queued elements, a pending Dequeue of rank 2 and its own
+    // Enqueue acknowledged by itself only
+    fn busy_replica() -> ProcessData {
+        let mut node = ProcessData::new(0, 3, &Config::default());
+        node.execute_locally(MessagePayload::new(1, 5, 1, 1, 0, vc(&[0, 1, 0])));
+        node.execute_locally(MessagePayload::new(1, 6, 2, 2, 0, vc(&[0, 0, 1])));
+        node.execute_locally(MessagePayload::new(4, 0, 2, 2, 0, vc(&[0, 1, 2])));
+        let enq_reqs =
+            node.execute_locally(MessagePayload::new(0, 7, 0, 0, 0, VectorClock::default()));
+        let ack = node.execute_locally(enq_reqs[0]);
+        node.execute_locally(ack[0]);
+        node.applied_lsn = 6;
+        node
+    }
+
+    fn assert_same_state(restored: &ProcessData, original: &ProcessData) {
+        assert_eq!(
+            serde_json::to_value(restored.snapshot()).unwrap(),
+            serde_json::to_value(original.snapshot()).unwrap()
+        );
+        assert!(restored.local_queue.iter().eq(original.local_queue.iter()));
+        assert_eq!(restored.digest.queue, original.digest.queue);
+    }
+
+    #[test]
+    fn a_saved_replica_restores_to_the_same_state() {
+        let dir = scratch_dir("snapshot_restore");
+        let original = busy_replica();
+        assert_eq!(original.local_queue.len(), 3);
+        assert_eq!(original.pending_dequeues.len(), 1);
+        assert!(original.enq_pending);
+        assert_eq!(original.enq_acked, vec![true, false, false]);
+
+        let path = Snapshot::path(&dir, 0);
+        original.snapshot().save(&path).unwrap();
+        let mut restored = ProcessData::new(0, 3, &Config::default());
+        restored.restore(Snapshot::load(&path).unwrap()).unwrap();
+        assert_same_state(&restored, &original);
+        assert_eq!(restored.applied_enqueues, vec![1, 1, 1]);
+        assert_eq!(restored.applied_lsn, 6);
+
+        // Another process cannot take it
+        let mut other = ProcessData::new(1, 3, &Config::default());
+        assert!(other.restore(Snapshot::load(&path).unwrap()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_leftover_temporary_file_does_not_affect_saves_or_loads() {
+        let dir = scratch_dir("snapshot_leftover");
+        let path = Snapshot::path(&dir, 0);
+        let tmp_path = path.with_extension("json.tmp");
+        fs::create_dir_all(&dir).unwrap();
+        let first = ProcessData::new(0, 3, &Config::default());
+        first.snapshot().save(&path).unwrap();
+
+        // A later save was cut short, leaving half a file next to the complete snapshot
+        let original = busy_replica();
+        let json = serde_json::to_vec_pretty(&original.snapshot()).unwrap();
+        fs::write(&tmp_path, &json[..json.len() / 2]).unwrap();
+        let mut restored = busy_replica();
+        restored.restore(Snapshot::load(&path).unwrap()).unwrap();
+        assert_same_state(&restored, &first);
+
+        // The next save replaces both
+        original.snapshot().save(&path).unwrap();
+        assert!(!tmp_path.exists());
+        let mut restored = ProcessData::new(0, 3, &Config::default());
+        restored.restore(Snapshot::load(&path).unwrap()).unwrap();
+        assert_same_state(&restored, &original);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
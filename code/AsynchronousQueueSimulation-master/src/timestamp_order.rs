@@ -0,0 +1,143 @@
+use mpi::Rank;
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+use crate::message_payload::VectorClock;
+
+// Total order used to linearize operations. Every variant must be consistent with causality:
+// if one timestamp happens before another, it is also ordered before it.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TimestampOrder {
+    // Order by the first differing clock entry (the paper's order)
+    #[default]
+    Lexicographic,
+    // Order by the sum of the clock entries, breaking ties by the invoking rank
+    SumThenRank,
+}
+
+// Sort key for a (timestamp, invoker) pair; comparing keys is the same as comparing the
+// pairs under the order that produced them. The trailing invoker breaks ties between equal
+// timestamps, so every order is total on the pairs.
+pub type OrderKey = (i64, Rank, VectorClock, Rank);
+
+impl TimestampOrder {
+    pub fn key(&self, ts: &VectorClock, invoker: Rank) -> OrderKey {
+        match self {
+            TimestampOrder::Lexicographic => (0, 0, *ts, invoker),
+            TimestampOrder::SumThenRank => {
+                let sum = ts.clock[..ts.size].iter().map(|&x| x as i64).sum();
+                (sum, invoker, *ts, invoker)
+            }
+        }
+    }
+
+    pub fn compare(
+        &self,
+        ts: &VectorClock,
+        invoker: Rank,
+        other_ts: &VectorClock,
+        other_invoker: Rank,
+    ) -> Ordering {
+        self.key(ts, invoker)
+            .cmp(&self.key(other_ts, other_invoker))
+    }
+}
+
+impl FromStr for TimestampOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lexicographic" => Ok(TimestampOrder::Lexicographic),
+            "sum-then-rank" => Ok(TimestampOrder::SumThenRank),
+            _ => Err(format!("Unknown timestamp order: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORDERS: [TimestampOrder; 2] =
+        [TimestampOrder::Lexicographic, TimestampOrder::SumThenRank];
+
+    fn vc(entries: &[i32]) -> VectorClock {
+        VectorClock::try_from(entries.to_vec()).unwrap()
+    }
+
+    // Every clock with three entries in 0..3, plus shorter ones
+    fn clocks() -> Vec<VectorClock> {
+        let mut clocks = vec![VectorClock::default(), vc(&[1]), vc(&[0, 2])];
+        for i in 0..27 {
+            clocks.push(vc(&[i / 9, i / 3 % 3, i % 3]));
+        }
+        clocks
+    }
+
+    #[test]
+    fn orders_extend_happens_before() {
+        let clocks = clocks();
+        for order in ORDERS {
+            for a in clocks.iter() {
+                for b in clocks.iter().filter(|b| a.happens_before(b)) {
+                    for (x, y) in [(0, 0), (0, 2), (2, 0)] {
+                        assert_eq!(
+                            order.compare(a, x, b, y),
+                            Ordering::Less,
+                            "{:?}: {:?} by {} vs {:?} by {}",
+                            order,
+                            a,
+                            x,
+                            b,
+                            y
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn orders_are_total_on_timestamp_and_invoker() {
+        let clocks = clocks();
+        for order in ORDERS {
+            for a in clocks.iter() {
+                for b in clocks.iter() {
+                    for x in 0..3 {
+                        for y in 0..3 {
+                            let forward = order.compare(a, x, b, y);
+                            assert_eq!(forward, order.compare(b, y, a, x).reverse());
+                            assert_eq!(forward == Ordering::Equal, a == b && x == y);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn equal_timestamps_are_ordered_by_invoker() {
+        let ts = vc(&[1, 2, 0]);
+        for order in ORDERS {
+            assert_eq!(order.compare(&ts, 0, &ts, 1), Ordering::Less);
+            assert_eq!(order.compare(&ts, 2, &ts, 1), Ordering::Greater);
+            assert_eq!(order.compare(&ts, 1, &ts, 1), Ordering::Equal);
+        }
+        // Sums tie first under SumThenRank, whatever the clocks
+        let order = TimestampOrder::SumThenRank;
+        assert_eq!(
+            order.compare(&vc(&[3, 0]), 0, &vc(&[0, 3]), 1),
+            Ordering::Less
+        );
+        assert_eq!(
+            order.compare(&vc(&[0, 3]), 0, &vc(&[3, 0]), 1),
+            Ordering::Less
+        );
+        let order = TimestampOrder::Lexicographic;
+        assert_eq!(
+            order.compare(&vc(&[3, 0]), 0, &vc(&[0, 3]), 1),
+            Ordering::Greater
+        );
+    }
+}
@@ -0,0 +1,367 @@
+use mpi::Rank;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::message_payload::MessagePayload;
+use crate::process_data::ProcessData;
+use crate::snapshot::Snapshot;
+
+// One applied message. Log sequence numbers (lsn) increase by one per entry and keep
+// counting across snapshots, so a snapshot records the last lsn it already contains.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub lsn: u64,
+    pub message: MessagePayload,
+}
+
+// Append-only JSON lines log of every message handed to `execute_locally`. Entries are
+// synced to disk before the message is applied, so nothing a peer has been acknowledged
+// for can be lost by a crash.
+pub struct WriteAheadLog {
+    file: File,
+    path: PathBuf,
+    next_lsn: u64,
+}
+
+impl WriteAheadLog {
+    pub fn path(dir: &Path, rank: Rank) -> PathBuf {
+        dir.join(format!("rank{}.wal", rank))
+    }
+
+    pub fn open(path: &Path, next_lsn: u64) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(WriteAheadLog {
+            file,
+            path: path.to_path_buf(),
+            next_lsn,
+        })
+    }
+
+    // Returns the lsn given to `message`
+    pub fn append(&mut self, message: &MessagePayload) -> io::Result<u64> {
+        let entry = LogEntry {
+            lsn: self.next_lsn,
+            message: *message,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        self.next_lsn += 1;
+        Ok(entry.lsn)
+    }
+
+    // Called once a snapshot holding every entry so far is on disk
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.file = File::create(&self.path)?;
+        self.file.sync_all()
+    }
+
+    // Reads every complete entry, see `scan`
+    pub fn read(path: &Path) -> io::Result<Vec<LogEntry>> {
+        Ok(Self::scan(path)?.entries)
+    }
+
+    // Reads the log, checking that its entries are well formed and consecutive. A crash
+    // mid-append leaves a final line without its newline; that entry was never synced, so
+    // its message was not applied, and it is reported as a torn tail rather than an error.
+    pub fn scan(path: &Path) -> io::Result<LogContents> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let corrupt = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut contents = LogContents {
+            entries: Vec::new(),
+            valid_len: 0,
+            torn_tail: false,
+        };
+        let mut rest = &bytes[..];
+        while !rest.is_empty() {
+            let Some(end) = rest.iter().position(|&b| b == b'\n') else {
+                contents.torn_tail = true;
+                break;
+            };
+            let entry: LogEntry = serde_json::from_slice(&rest[..end]).map_err(|e| {
+                corrupt(format!(
+                    "{}: bad entry after lsn {}: {}",
+                    path.display(),
+                    contents
+                        .entries
+                        .last()
+                        .map_or(0, |entry: &LogEntry| entry.lsn),
+                    e
+                ))
+            })?;
+            if let Some(last) = contents.entries.last() {
+                if entry.lsn != last.lsn + 1 {
+                    return Err(corrupt(format!(
+                        "{}: lsn {} follows lsn {}",
+                        path.display(),
+                        entry.lsn,
+                        last.lsn
+                    )));
+                }
+            }
+            contents.entries.push(entry);
+            contents.valid_len += end as u64 + 1;
+            rest = &rest[end + 1..];
+        }
+        Ok(contents)
+    }
+
+    // Cuts the log back to its first `len` bytes, dropping a torn tail so later appends
+    // start on a fresh line
+    fn cut(path: &Path, len: u64) -> io::Result<()> {
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(len)?;
+        file.sync_all()
+    }
+}
+
+pub struct LogContents {
+    pub entries: Vec<LogEntry>,
+    // Bytes taken up by `entries`
+    pub valid_len: u64,
+    // The file ends in a partial entry past `valid_len`
+    pub torn_tail: bool,
+}
+
+// What recovery hands back to the MPI thread
+pub struct Recovered {
+    // The log to keep appending to
+    pub wal: Option<WriteAheadLog>,
+    pub resend: Vec<MessagePayload>,
+    // Past every client op id of a replayed invocation, so a later completion of one of
+    // those operations can never reach a new client
+    pub next_op_id: i32,
+}
+
+// Rebuilds a replica from its latest snapshot plus the log entries applied after it. It
+// returns the log to keep appending to along with the queue messages the replayed steps
+// produced. A crash between logging a message and sending what it produced would otherwise
+// lose those messages, so they are all sent again: receivers apply a repeated EnqReq once,
+// count one EnqAck per member and treat DeqReqs and DeqAcks as idempotent. Membership
+// messages are not repeated, since a repeated ViewChangeReq would start a second change.
+pub fn recover(
+    process_data: &mut ProcessData,
+    config: &Config,
+    rank: Rank,
+) -> io::Result<Recovered> {
+    let entries = match &config.wal_dir {
+        Some(dir) => {
+            let path = WriteAheadLog::path(dir, rank);
+            let contents = WriteAheadLog::scan(&path)?;
+            if contents.torn_tail {
+                warn!(
+                    path = %path.display(),
+                    last_lsn = contents.entries.last().map_or(0, |entry| entry.lsn),
+                    "discarding a partial final log entry left by a crash mid-append"
+                );
+                WriteAheadLog::cut(&path, contents.valid_len)?;
+            }
+            contents.entries
+        }
+        None => Vec::new(),
+    };
+    if let Some(dir) = &config.snapshot_dir {
+        let path = Snapshot::path(dir, rank);
+        if path.exists() {
+            process_data
+                .restore(Snapshot::load(&path)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            info!(path = %path.display(), "restored from snapshot");
+        } else if entries.first().is_none_or(|entry| entry.lsn != 1) {
+            // Without a snapshot only a log reaching back to the first message is complete
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "no snapshot at {} and no complete write-ahead log to restore from",
+                    path.display()
+                ),
+            ));
+        }
+    }
+
+    // Entries from the one after the snapshot onwards must all be there
+    if let Some(first) = entries.first() {
+        if first.lsn > process_data.applied_lsn + 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "write-ahead log starts at lsn {} but the replica has only applied up to lsn {}",
+                    first.lsn, process_data.applied_lsn
+                ),
+            ));
+        }
+    }
+
+    let dir = match &config.wal_dir {
+        Some(dir) => dir,
+        None => {
+            return Ok(Recovered {
+                wal: None,
+                resend: Vec::new(),
+                next_op_id: 0,
+            })
+        }
+    };
+    let path = WriteAheadLog::path(dir, rank);
+    let mut next_lsn = process_data.applied_lsn + 1;
+    let mut replayed = 0;
+    let mut resend = Vec::new();
+    let mut next_op_id = 0;
+    for entry in entries {
+        if entry.lsn > process_data.applied_lsn {
+            next_op_id = next_op_id.max(entry.message.op_id + 1);
+            resend.extend(
+                process_data
+                    .execute_locally(entry.message)
+                    .into_iter()
+                    .filter(|message| matches!(message.message, 1 | 2 | 4 | 5)),
+            );
+            process_data.applied_lsn = entry.lsn;
+            replayed += 1;
+        }
+        next_lsn = next_lsn.max(entry.lsn + 1);
+    }
+    // Dequeues re-executed here were already reported before the crash, and the clients of
+    // operations that returned during replay are gone
+    process_data.executed_dequeues.clear();
+    process_data.completions.clear();
+    if replayed > 0 {
+        info!(replayed, resend = resend.len(), "replayed logged messages");
+    }
+    Ok(Recovered {
+        wal: Some(WriteAheadLog::open(&path, next_lsn)?),
+        resend,
+        next_op_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_payload::VectorClock;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("async_queue_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn replay_returns_the_messages_it_produced() {
+        let dir = scratch_dir("wal_resend");
+        let mut wal = WriteAheadLog::open(&WriteAheadLog::path(&dir, 0), 1).unwrap();
+        wal.append(&MessagePayload::new(0, 5, 0, 0, 0, VectorClock::default()).with_op_id(3))
+            .unwrap();
+        let config = Config {
+            wal_dir: Some(dir.clone()),
+            ..Config::default()
+        };
+        let mut process_data = ProcessData::new(0, 2, &config);
+        let recovered = recover(&mut process_data, &config, 0).unwrap();
+        assert_eq!(recovered.resend.len(), 2);
+        assert!(recovered.resend.iter().all(|message| message.message == 1));
+        assert_eq!(recovered.next_op_id, 4);
+        assert!(process_data.enq_pending);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restore_without_snapshot_or_full_log_fails() {
+        let dir = scratch_dir("wal_missing");
+        let config = Config {
+            snapshot_dir: Some(dir.clone()),
+            wal_dir: Some(dir.clone()),
+            restore: true,
+            ..Config::default()
+        };
+        let mut process_data = ProcessData::new(0, 2, &config);
+        assert!(recover(&mut process_data, &config, 0).is_err());
+    }
+
+    fn wal_config(dir: &Path) -> Config {
+        Config {
+            wal_dir: Some(dir.to_path_buf()),
+            restore: true,
+            ..Config::default()
+        }
+    }
+
+    fn log_with(dir: &Path, lsns: &[u64]) -> PathBuf {
+        let path = WriteAheadLog::path(dir, 0);
+        fs::create_dir_all(dir).unwrap();
+        let lines: Vec<String> = lsns
+            .iter()
+            .map(|&lsn| {
+                let message = MessagePayload::new(1, lsn as i32, 1, 1, 0, VectorClock::new(2));
+                serde_json::to_string(&LogEntry { lsn, message }).unwrap() + "\n"
+            })
+            .collect();
+        fs::write(&path, lines.concat()).unwrap();
+        path
+    }
+
+    #[test]
+    fn a_torn_final_entry_is_cut_off() {
+        let dir = scratch_dir("wal_torn");
+        let path = log_with(&dir, &[1, 2]);
+        let whole = fs::read(&path).unwrap().len() as u64;
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"lsn\":3,\"mess").unwrap();
+        drop(file);
+
+        let config = wal_config(&dir);
+        let mut process_data = ProcessData::new(0, 2, &config);
+        let mut recovered = recover(&mut process_data, &config, 0).unwrap();
+        assert_eq!(process_data.applied_lsn, 2);
+        assert_eq!(fs::metadata(&path).unwrap().len(), whole);
+        // Appends continue on a line of their own
+        recovered
+            .wal
+            .as_mut()
+            .unwrap()
+            .append(&MessagePayload::new(1, 9, 1, 1, 0, VectorClock::new(2)))
+            .unwrap();
+        let lsns: Vec<u64> = WriteAheadLog::read(&path)
+            .unwrap()
+            .iter()
+            .map(|e| e.lsn)
+            .collect();
+        assert_eq!(lsns, vec![1, 2, 3]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_or_partial_logs_are_reported() {
+        let dir = scratch_dir("wal_corrupt");
+        let config = wal_config(&dir);
+
+        let path = log_with(&dir, &[1, 2]);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"garbage\n").unwrap();
+        drop(file);
+        let mut process_data = ProcessData::new(0, 2, &config);
+        assert!(recover(&mut process_data, &config, 0).is_err());
+
+        log_with(&dir, &[1, 3]);
+        let mut process_data = ProcessData::new(0, 2, &config);
+        assert!(recover(&mut process_data, &config, 0).is_err());
+
+        // Without a snapshot the log has to start at the first message
+        log_with(&dir, &[2, 3]);
+        let mut process_data = ProcessData::new(0, 2, &config);
+        assert!(recover(&mut process_data, &config, 0).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}